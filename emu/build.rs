@@ -0,0 +1,56 @@
+//! Assembles the programs in `asm/` into `OUT_DIR`, so the examples can include their images
+//!
+//! The assembler only uses `std`, so its modules are built into this script from their source.
+
+// Only part of each module is needed here
+#[allow(dead_code)]
+#[path = "src/asm.rs"]
+mod asm;
+#[allow(dead_code)]
+#[path = "src/isa.rs"]
+mod isa;
+#[allow(dead_code)]
+#[path = "src/located.rs"]
+mod located;
+#[allow(dead_code)]
+#[path = "src/symbols.rs"]
+mod symbols;
+
+use std::{env, error::Error, fs, path::PathBuf, process};
+
+/// The library's sources that are part of this script
+const MODULES: &[&str] = &[
+    "src/asm.rs",
+    "src/isa.rs",
+    "src/located.rs",
+    "src/symbols.rs",
+];
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let asm = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?).join("../asm");
+    let out = PathBuf::from(env::var("OUT_DIR")?);
+    println!("cargo:rerun-if-changed={}", asm.display());
+    for module in MODULES {
+        println!("cargo:rerun-if-changed={}", module);
+    }
+
+    let mut sources = Vec::new();
+    asm::find_sources(&asm, &mut sources)?;
+    for source in sources {
+        let (version, stem) = match (asm::version_of(&source), source.file_stem()) {
+            (Some(version), Some(stem)) => (version, stem.to_string_lossy()),
+            _ => continue,
+        };
+        // `main` returning the error would only show its `Debug` form
+        let image = match asm::assemble_file(version, &source) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("error: could not assemble {}", e);
+                process::exit(1);
+            }
+        };
+        let name = format!("{:?}_{}.bin", version, stem).to_lowercase();
+        fs::write(out.join(name), image)?;
+    }
+    Ok(())
+}
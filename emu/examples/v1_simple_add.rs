@@ -1,9 +1,14 @@
 use puttpc_emu::{v1::PuttPc, Machine};
+use std::error::Error;
 
-fn main() {
+/// `asm/v1/simple_add.S`, assembled by the build script
+const IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/v1_simple_add.bin"));
+
+fn main() -> Result<(), Box<dyn Error>> {
     let mut puttpc = PuttPc::new();
-    let output = puttpc.run_with_input(include_bytes!("v1_simple_add.bin"));
+    let output = puttpc.run_with_input(IMAGE)?;
     for o in output {
         println!("0x{:x?}", o);
     }
+    Ok(())
 }
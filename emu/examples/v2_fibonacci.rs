@@ -1,8 +1,13 @@
 use puttpc_emu::{v2::PuttPc, Machine};
+use std::error::Error;
 
-fn main() {
+/// `asm/v2/fibonacci.S`, assembled by the build script
+const IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/v2_fibonacci.bin"));
+
+fn main() -> Result<(), Box<dyn Error>> {
     let puttpc = PuttPc::new();
-    for o in puttpc.into_iter_with_input(include_bytes!("v2_fibonacci.bin")) {
-        println!("0x{:02x?}", o);
+    for o in puttpc.into_iter_with_input(IMAGE)? {
+        println!("0x{:02x?}", o?);
     }
+    Ok(())
 }
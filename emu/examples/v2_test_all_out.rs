@@ -1,13 +1,18 @@
 use puttpc_emu::{v2::PuttPc, Machine};
+use std::error::Error;
 
-fn main() {
+/// `asm/v2/test_all_out.S`, assembled by the build script
+const IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/v2_test_all_out.bin"));
+
+fn main() -> Result<(), Box<dyn Error>> {
     let mut puttpc = PuttPc::new();
 
-    puttpc.set_input(include_bytes!("v2_test_all_out.bin"));
+    puttpc.set_input(IMAGE)?;
 
     println!("{:#?}", puttpc);
     while !puttpc.is_halted() {
-        puttpc.step();
+        puttpc.step()?;
         println!("{:#?}", puttpc);
     }
+    Ok(())
 }
@@ -0,0 +1,1126 @@
+//! An assembler for the PuttPc dialects described by `asm/v*/ruledef.S`
+//!
+//! The syntax follows customasm closely enough to assemble the programs in `asm/`: labels,
+//! `#include`, `#once`, `#d`, `#addr` and `#res` directives, and expressions with size
+//! annotations like `` 43`8 ``. `#ruledef` blocks are skipped, since the rules for each version
+//! come from `isa`.
+
+// Only `std` and these dependency-free modules are used, so the build script can include this too
+use crate::{
+    isa::{self, Operand, Version},
+    located::Located,
+    symbols::Symbols,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(PathBuf, io::Error),
+    Syntax(String),
+    UnknownDirective(String),
+    NoMatchingRule(String),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    IncludeCycle(PathBuf),
+    OutOfRange {
+        value: i64,
+        bits: u32,
+    },
+    UnalignedData(u32),
+    /// Data, or a single size annotation, has more bits than memory
+    DataTooLarge {
+        max_bits: usize,
+    },
+    AddressBackwards {
+        from: usize,
+        to: usize,
+    },
+    ProgramTooLarge {
+        len: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "could not read `{}`", path.display()),
+            Self::Syntax(s) => write!(f, "syntax error: {}", s),
+            Self::UnknownDirective(d) => write!(f, "unknown directive `#{}`", d),
+            Self::NoMatchingRule(s) => write!(f, "no instruction matches `{}`", s),
+            Self::UnknownLabel(l) => write!(f, "unknown label `{}`", l),
+            Self::DuplicateLabel(l) => write!(f, "duplicate label `{}`", l),
+            Self::IncludeCycle(p) => write!(f, "`{}` includes itself", p.display()),
+            Self::OutOfRange { value, bits } => {
+                write!(f, "value {} does not fit in {} bits", value, bits)
+            }
+            Self::UnalignedData(bits) => write!(f, "data is {} bits, not whole bytes", bits),
+            Self::DataTooLarge { max_bits } => {
+                write!(f, "data is more than the {} bits of memory", max_bits)
+            }
+            Self::AddressBackwards { from, to } => {
+                write!(f, "cannot move address backwards from {} to {}", from, to)
+            }
+            Self::ProgramTooLarge { len, max } => {
+                write!(f, "program is {} bytes, but memory is {} bytes", len, max)
+            }
+        }
+    }
+}
+
+impl error::Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub type Error = Located<ErrorKind>;
+
+/// Assemble source code for the given version
+///
+/// `#include` paths are resolved relative to the current directory.
+///
+/// # Errors
+///
+/// Returns an error if the source (or anything it includes) can't be read or assembled.
+pub fn assemble(version: Version, source: &str) -> Result<Vec<u8>, Error> {
+    let mut asm = Assembler::new(version);
+    asm.parse(source, None, Path::new("."))?;
+    Ok(asm.finish()?.0)
+}
+
+/// Assemble a source file for the given version
+///
+/// `#include` paths are resolved relative to the including file.
+///
+/// # Errors
+///
+/// Returns an error if the file (or anything it includes) can't be read or assembled.
+pub fn assemble_file(version: Version, path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let mut asm = Assembler::new(version);
+    asm.include(path.as_ref(), None)?;
+    Ok(asm.finish()?.0)
+}
+
+/// Assemble a source file, also returning its labels and the source line of everything emitted
+///
+/// # Errors
+///
+/// Returns an error if the file (or anything it includes) can't be read or assembled.
+pub fn assemble_file_with_symbols(
+    version: Version,
+    path: impl AsRef<Path>,
+) -> Result<(Vec<u8>, Symbols), Error> {
+    let mut asm = Assembler::new(version);
+    asm.include(path.as_ref(), None)?;
+    asm.finish()
+}
+
+/// Add `path` to `sources` if it's a file, or every .S file under it if it's a directory
+///
+/// Files in a directory are added in sorted order, and their extension can be in any case. Hidden
+/// directories and `target` directories are skipped, as they hold version control and build
+/// output rather than programs.
+///
+/// # Errors
+///
+/// Returns an error if a directory can't be read.
+pub fn find_sources(path: &Path, sources: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        sources.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)
+        .and_then(|dir| {
+            dir.map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            if !name.starts_with('.') && name != "target" {
+                find_sources(&entry, sources)?;
+            }
+        } else if entry
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("s"))
+        {
+            sources.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// The version a program is for, from the nearest directory named after one, like `asm/v2`
+#[must_use]
+pub fn version_of(path: &Path) -> Option<Version> {
+    path.ancestors()
+        .skip(1)
+        .filter_map(|dir| dir.file_name()?.to_str())
+        .find_map(Version::from_name)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number { value: i64, digit_bits: Option<u32> },
+    Str(String),
+    Punct(&'static str),
+}
+
+const PUNCTS: &[&str] = &[
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", "{", "}", ",", ":", "$", "`",
+];
+
+fn tokenize(line: &str) -> Result<Vec<Token>, ErrorKind> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(i, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, c)) => s.push(c),
+                    None => return Err(ErrorKind::Syntax("unterminated string".into())),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(parse_number(&line[i..end])?);
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let mut end = i;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(line[i..end].to_string()));
+        } else if let Some(p) = PUNCTS.iter().find(|p| line[i..].starts_with(**p)) {
+            for _ in 0..p.len() {
+                chars.next();
+            }
+            tokens.push(Token::Punct(p));
+        } else {
+            return Err(ErrorKind::Syntax(format!("unexpected character `{}`", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(s: &str) -> Result<Token, ErrorKind> {
+    let digits = s.replace('_', "");
+    let (radix, digits, bits_per_digit) = if let Some(d) = digits.strip_prefix("0x") {
+        (16, d.to_string(), Some(4))
+    } else if let Some(d) = digits.strip_prefix("0b") {
+        (2, d.to_string(), Some(1))
+    } else {
+        (10, digits, None)
+    };
+    let value = i64::from_str_radix(&digits, radix)
+        .map_err(|_| ErrorKind::Syntax(format!("invalid number `{}`", s)))?;
+    let digit_bits = bits_per_digit.map(|b| b * u32::try_from(digits.len()).unwrap_or(u32::MAX));
+    Ok(Token::Number { value, digit_bits })
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Label(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, labels: &HashMap<String, i64>) -> Result<i64, ErrorKind> {
+        Ok(match self {
+            Self::Number(n) => *n,
+            Self::Label(l) => *labels
+                .get(l)
+                .ok_or_else(|| ErrorKind::UnknownLabel(l.clone()))?,
+            Self::Unary(op, e) => {
+                let e = e.eval(labels)?;
+                match *op {
+                    "-" => e.wrapping_neg(),
+                    _ => !e,
+                }
+            }
+            Self::Binary(op, l, r) => {
+                let l = l.eval(labels)?;
+                let r = r.eval(labels)?;
+                match *op {
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "*" => l.wrapping_mul(r),
+                    "/" | "%" if r == 0 => {
+                        return Err(ErrorKind::Syntax("division by zero".into()))
+                    }
+                    "/" => l.wrapping_div(r),
+                    "%" => l.wrapping_rem(r),
+                    "&" => l & r,
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "<<" => l.wrapping_shl(u32::try_from(r).unwrap_or(u32::MAX)),
+                    _ => l.wrapping_shr(u32::try_from(r).unwrap_or(u32::MAX)),
+                }
+            }
+        })
+    }
+}
+
+/// An expression, with the number of bits it should occupy if it was given or can be inferred
+#[derive(Debug, Clone)]
+struct Sized {
+    expr: Expr,
+    bits: Option<u32>,
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    /// The last non-local label, used to qualify local `.labels`
+    scope: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ErrorKind> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(ErrorKind::Syntax(format!("expected `{}`", punct)))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ErrorKind> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, ErrorKind> {
+        const LEVELS: &[&[&str]] = &[
+            &["|"],
+            &["^"],
+            &["&"],
+            &["<<", ">>"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Punct(p)) = self.peek() {
+            if !LEVELS[level].contains(p) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(p, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ErrorKind> {
+        for op in ["-", "~"] {
+            if self.eat(op) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ErrorKind> {
+        match self.peek() {
+            Some(Token::Number { value, .. }) => {
+                self.pos += 1;
+                Ok(Expr::Number(*value))
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(Expr::Label(qualify(self.scope, name)))
+            }
+            Some(Token::Punct("(")) => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(Token::Punct("{")) => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect("}")?;
+                Ok(e)
+            }
+            Some(t) => Err(ErrorKind::Syntax(format!("unexpected {:?}", t))),
+            None => Err(ErrorKind::Syntax("expected an expression".into())),
+        }
+    }
+
+    /// An expression with an optional `` `bits `` size annotation
+    fn sized(&mut self) -> Result<Sized, ErrorKind> {
+        let digit_bits = match self.peek() {
+            Some(Token::Number { digit_bits, .. }) => *digit_bits,
+            _ => None,
+        };
+        let start = self.pos;
+        let expr = self.expr()?;
+        let bits = if self.eat("`") {
+            match self.peek() {
+                Some(Token::Number { value, .. }) => {
+                    self.pos += 1;
+                    Some(
+                        u32::try_from(*value)
+                            .map_err(|_| ErrorKind::Syntax("invalid size".into()))?,
+                    )
+                }
+                _ => return Err(ErrorKind::Syntax("expected a size after '`'".into())),
+            }
+        } else if self.pos == start + 1 {
+            // A lone hex or binary literal is as wide as its digits
+            digit_bits
+        } else {
+            None
+        };
+        Ok(Sized { expr, bits })
+    }
+}
+
+fn qualify(scope: &str, name: &str) -> String {
+    if name.starts_with('.') {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Truncate `value` to `bits` bits, checking that it fits as either a signed or unsigned value
+fn truncate(value: i64, bits: u32) -> Result<u64, ErrorKind> {
+    if bits == 0 {
+        return Err(ErrorKind::OutOfRange { value, bits });
+    }
+    let fits = if bits >= 64 {
+        true
+    } else {
+        let max = 1_i64 << bits;
+        let min = -(1_i64 << (bits - 1).min(62));
+        (min..max).contains(&value)
+    };
+    if !fits {
+        return Err(ErrorKind::OutOfRange { value, bits });
+    }
+    #[allow(clippy::cast_sign_loss)]
+    let value = value as u64;
+    Ok(if bits >= 64 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    })
+}
+
+/// The number of bits in a `#d`, checking they fit in memory before anything is allocated
+fn data_bits(data: &[Sized], version: Version) -> Result<u32, ErrorKind> {
+    let max_bits = version.memory_size() * 8;
+    data.iter().try_fold(0_u32, |sum, d| {
+        sum.checked_add(d.bits.unwrap_or(8))
+            .filter(|bits| *bits as usize <= max_bits)
+            .ok_or(ErrorKind::DataTooLarge { max_bits })
+    })
+}
+
+#[derive(Debug)]
+enum ItemKind {
+    Instruction {
+        opcode: u8,
+        operands: Vec<(Operand, Expr)>,
+    },
+    Data(Vec<Sized>),
+}
+
+#[derive(Debug)]
+struct Item {
+    file: Option<PathBuf>,
+    line: usize,
+    /// The source line, without its comment
+    text: String,
+    address: usize,
+    kind: ItemKind,
+}
+
+struct Assembler {
+    version: Version,
+    items: Vec<Item>,
+    labels: HashMap<String, i64>,
+    scope: String,
+    address: usize,
+    once: HashSet<PathBuf>,
+    /// The files being included, outermost first, to catch `#include` cycles
+    including: Vec<PathBuf>,
+}
+
+impl Assembler {
+    fn new(version: Version) -> Self {
+        Self {
+            version,
+            items: Vec::new(),
+            labels: HashMap::new(),
+            scope: String::new(),
+            address: 0,
+            once: HashSet::new(),
+            including: Vec::new(),
+        }
+    }
+
+    fn include(&mut self, path: &Path, from: Option<(&Path, usize)>) -> Result<(), Error> {
+        let error = |kind| Error {
+            file: from.map(|(f, _)| f.to_path_buf()),
+            line: from.map_or(0, |(_, l)| l),
+            kind,
+        };
+
+        let canonical =
+            fs::canonicalize(path).map_err(|e| error(ErrorKind::Io(path.to_path_buf(), e)))?;
+        if self.once.contains(&canonical) {
+            return Ok(());
+        }
+        if self.including.contains(&canonical) {
+            return Err(error(ErrorKind::IncludeCycle(path.to_path_buf())));
+        }
+        let source =
+            fs::read_to_string(path).map_err(|e| error(ErrorKind::Io(path.to_path_buf(), e)))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        self.including.push(canonical);
+        let result = self.parse(&source, Some(path), dir);
+        self.including.pop();
+        result
+    }
+
+    fn parse(&mut self, source: &str, file: Option<&Path>, dir: &Path) -> Result<(), Error> {
+        let mut lines = source.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            let line_no = i + 1;
+            let error = |kind| Error {
+                file: file.map(Path::to_path_buf),
+                line: line_no,
+                kind,
+            };
+
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(directive) = line.strip_prefix('#') {
+                let name = directive
+                    .split(|c: char| !c.is_alphanumeric())
+                    .next()
+                    .unwrap_or_default();
+                let rest = directive[name.len()..].trim();
+                match name {
+                    "once" => {
+                        if let Some(file) = file {
+                            let canonical = fs::canonicalize(file)
+                                .map_err(|e| error(ErrorKind::Io(file.to_path_buf(), e)))?;
+                            self.once.insert(canonical);
+                        }
+                    }
+                    "include" => {
+                        let tokens = tokenize(rest).map_err(error)?;
+                        match tokens.as_slice() {
+                            [Token::Str(s)] => {
+                                let from = file.map(|f| (f, line_no));
+                                self.include(&dir.join(s), from)?;
+                            }
+                            _ => {
+                                return Err(error(ErrorKind::Syntax(
+                                    "expected a quoted path".into(),
+                                )))
+                            }
+                        }
+                    }
+                    "ruledef" => {
+                        // The rules come from `isa`, so skip to the closing brace
+                        let braces = |s: &str| {
+                            let s = s.split(';').next().unwrap_or_default();
+                            (s.matches('{').count(), s.matches('}').count())
+                        };
+                        let (mut open, mut close) = braces(rest);
+                        while open == 0 || open > close {
+                            match lines.next() {
+                                Some((_, line)) => {
+                                    let (o, c) = braces(line);
+                                    open += o;
+                                    close += c;
+                                }
+                                None => break,
+                            }
+                        }
+                    }
+                    _ => self
+                        .directive(name, rest, file, line_no, line)
+                        .map_err(error)?,
+                }
+                continue;
+            }
+
+            let tokens = tokenize(line).map_err(error)?;
+            let mut tokens = tokens.as_slice();
+            while let [Token::Ident(name), Token::Punct(":"), rest @ ..] = tokens {
+                if !name.starts_with('.') {
+                    self.scope = name.clone();
+                }
+                let name = qualify(&self.scope, name);
+                #[allow(clippy::cast_possible_wrap)]
+                let address = self.address as i64;
+                if self.labels.insert(name.clone(), address).is_some() {
+                    return Err(error(ErrorKind::DuplicateLabel(name)));
+                }
+                tokens = rest;
+            }
+
+            if !tokens.is_empty() {
+                let kind = self.instruction(tokens, line).map_err(error)?;
+                self.push(kind, file, line_no, line);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn directive(
+        &mut self,
+        name: &str,
+        rest: &str,
+        file: Option<&Path>,
+        line: usize,
+        text: &str,
+    ) -> Result<(), ErrorKind> {
+        let tokens = tokenize(rest)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            scope: &self.scope,
+        };
+
+        match name {
+            "d" | "d8" => {
+                let mut data = Vec::new();
+                loop {
+                    let mut sized = parser.sized()?;
+                    if name == "d8" {
+                        sized.bits = Some(8);
+                    }
+                    data.push(sized);
+                    if !parser.eat(",") {
+                        break;
+                    }
+                }
+                if !parser.is_done() {
+                    return Err(ErrorKind::Syntax("expected `,`".into()));
+                }
+                data_bits(&data, self.version)?;
+                self.push(ItemKind::Data(data), file, line, text);
+            }
+            "addr" | "res" => {
+                let value = parser.expr()?.eval(&self.labels)?;
+                if !parser.is_done() {
+                    return Err(ErrorKind::Syntax("trailing tokens".into()));
+                }
+                let value = usize::try_from(value)
+                    .map_err(|_| ErrorKind::OutOfRange { value, bits: 64 })?;
+                let to = if name == "addr" {
+                    value
+                } else {
+                    self.address + value
+                };
+                if to < self.address {
+                    return Err(ErrorKind::AddressBackwards {
+                        from: self.address,
+                        to,
+                    });
+                }
+                self.address = to;
+            }
+            _ => return Err(ErrorKind::UnknownDirective(name.to_string())),
+        }
+
+        Ok(())
+    }
+
+    fn instruction(&self, tokens: &[Token], line: &str) -> Result<ItemKind, ErrorKind> {
+        let (mnemonic, tokens) = match tokens {
+            [Token::Ident(m), rest @ ..] => (m.to_lowercase(), rest),
+            _ => return Err(ErrorKind::Syntax("expected a mnemonic".into())),
+        };
+
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            scope: &self.scope,
+        };
+        // The operands as written, with `None` for a bare expression
+        let mut operands: Vec<(Option<Operand>, Option<Expr>)> = Vec::new();
+        while !parser.is_done() {
+            if parser.eat("%") {
+                match parser.peek() {
+                    Some(Token::Ident(r)) if r.eq_ignore_ascii_case("a") => {
+                        operands.push((Some(Operand::A), None));
+                    }
+                    Some(Token::Ident(r)) if r.eq_ignore_ascii_case("b") => {
+                        operands.push((Some(Operand::B), None));
+                    }
+                    _ => return Err(ErrorKind::Syntax("expected `%a` or `%b`".into())),
+                }
+                parser.pos += 1;
+            } else if parser.eat("$") {
+                operands.push((Some(Operand::Address), Some(parser.expr()?)));
+            } else {
+                operands.push((None, Some(parser.expr()?)));
+            }
+            parser.eat(",");
+        }
+
+        let matches = |rule: &[Operand]| {
+            rule.len() == operands.len()
+                && rule.iter().zip(&operands).all(|(r, (o, _))| match o {
+                    Some(o) => o == r && (self.version == Version::V3 || *o != Operand::Address),
+                    None => {
+                        *r == Operand::Value
+                            || (*r == Operand::Address && self.version != Version::V3)
+                    }
+                })
+        };
+        let (opcode, _, rule) = isa::rules(self.version)
+            .iter()
+            .find(|(_, m, rule)| *m == mnemonic && matches(rule))
+            .ok_or_else(|| ErrorKind::NoMatchingRule(line.to_string()))?;
+
+        let operands = rule
+            .iter()
+            .zip(operands)
+            .filter_map(|(r, (_, e))| Some((*r, e?)))
+            .collect();
+        Ok(ItemKind::Instruction {
+            opcode: *opcode,
+            operands,
+        })
+    }
+
+    fn push(&mut self, kind: ItemKind, file: Option<&Path>, line: usize, text: &str) {
+        let address = self.address;
+        self.address += match &kind {
+            ItemKind::Instruction { operands, .. } => match self.version {
+                Version::V1 | Version::V2 => 1,
+                Version::V3 => 1 + operands.len(),
+            },
+            ItemKind::Data(data) => {
+                let bits =
+                    data_bits(data, self.version).expect("data is checked before it's pushed");
+                // Unaligned data is reported when it is emitted
+                (bits as usize).div_ceil(8)
+            }
+        };
+        self.items.push(Item {
+            file: file.map(Path::to_path_buf),
+            line,
+            text: text.to_string(),
+            address,
+            kind,
+        });
+    }
+
+    fn finish(self) -> Result<(Vec<u8>, Symbols), Error> {
+        let max = self.version.memory_size();
+        if self.address > max {
+            return Err(Error {
+                file: None,
+                line: 0,
+                kind: ErrorKind::ProgramTooLarge {
+                    len: self.address,
+                    max,
+                },
+            });
+        }
+
+        // Sorted, so labels sharing an address always come out in the same order
+        let mut labels: Vec<_> = self.labels.iter().map(|(n, a)| (*a, n)).collect();
+        labels.sort();
+        let mut symbols = Symbols::default();
+        for (address, name) in labels {
+            if let Ok(address) = usize::try_from(address) {
+                symbols.add_label(name, address);
+            }
+        }
+
+        let mut output = vec![0; self.address];
+        for item in &self.items {
+            let bytes = self.emit(item).map_err(|kind| Error {
+                file: item.file.clone(),
+                line: item.line,
+                kind,
+            })?;
+            output[item.address..item.address + bytes.len()].copy_from_slice(&bytes);
+            symbols.add_source(item.address, bytes.len(), &item.text);
+        }
+
+        Ok((output, symbols))
+    }
+
+    fn emit(&self, item: &Item) -> Result<Vec<u8>, ErrorKind> {
+        match &item.kind {
+            ItemKind::Instruction { opcode, operands } => match self.version {
+                Version::V1 | Version::V2 => {
+                    let operand = match operands.first() {
+                        Some((_, e)) => truncate(e.eval(&self.labels)?, 4)?,
+                        None => 0,
+                    };
+                    #[allow(clippy::cast_possible_truncation)]
+                    Ok(vec![opcode << 4 | operand as u8])
+                }
+                Version::V3 => {
+                    let mut bytes = vec![*opcode];
+                    for (_, e) in operands {
+                        #[allow(clippy::cast_possible_truncation)]
+                        bytes.push(truncate(e.eval(&self.labels)?, 8)? as u8);
+                    }
+                    Ok(bytes)
+                }
+            },
+            ItemKind::Data(data) => {
+                let mut bits = Vec::new();
+                for d in data {
+                    let size = d.bits.unwrap_or(8);
+                    let value = truncate(d.expr.eval(&self.labels)?, size)?;
+                    bits.extend((0..size).rev().map(|b| b < 64 && value >> b & 1 == 1));
+                }
+                if bits.len() % 8 != 0 {
+                    return Err(ErrorKind::UnalignedData(
+                        u32::try_from(bits.len()).unwrap_or(u32::MAX),
+                    ));
+                }
+                Ok(bits
+                    .chunks(8)
+                    .map(|byte| byte.iter().fold(0, |acc, &b| acc << 1 | u8::from(b)))
+                    .collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn v3(source: &str) -> Vec<u8> {
+        assemble(Version::V3, source).unwrap()
+    }
+
+    fn v3_error(source: &str) -> ErrorKind {
+        assemble(Version::V3, source).unwrap_err().kind
+    }
+
+    /// Write `files` into a fresh directory, returning it
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("puttpc_asm_{}_{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(v3("#d 1 + 2 * 3, (1 + 2) * 3"), [7, 9]);
+        assert_eq!(
+            v3("#d 1 << 4 | 1, 0xf0 & 0x3c ^ 0x01, 7 / 2, 7 % 2"),
+            [0x11, 0x31, 3, 1]
+        );
+        assert_eq!(v3("#d -1, ~0`8, -(2 - 5)"), [0xff, 0xff, 3]);
+        assert!(matches!(v3_error("#d 1 / 0"), ErrorKind::Syntax(_)));
+        assert!(matches!(v3_error("#d (1 + 2"), ErrorKind::Syntax(_)));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(v3("#d 0x1234"), [0x12, 0x34]);
+        assert_eq!(v3("#d 0b1010, 0x5"), [0xa5]);
+        assert_eq!(v3("#d 5`4, 3`4, 0x1`16"), [0x53, 0x00, 0x01]);
+        assert_eq!(v3("#d8 1, 2"), [1, 2]);
+        assert!(matches!(v3_error("#d 0b101"), ErrorKind::UnalignedData(3)));
+        assert!(matches!(
+            v3_error("#d 5`0"),
+            ErrorKind::OutOfRange { value: 5, bits: 0 }
+        ));
+        assert!(matches!(
+            v3_error("#d 0`0"),
+            ErrorKind::OutOfRange { value: 0, bits: 0 }
+        ));
+        assert!(matches!(
+            v3_error("#d 256"),
+            ErrorKind::OutOfRange {
+                value: 256,
+                bits: 8
+            }
+        ));
+    }
+
+    #[test]
+    fn data_larger_than_memory() {
+        for source in [
+            "#d 1`4294967295, 1`8",
+            "#d 1`2049",
+            "#d 0`2048, 0`8",
+            // Hex literals are as wide as their digits, leading zeros included
+            &format!("#d 0x{:0>600}", 1),
+        ] {
+            let e = assemble(Version::V3, source).unwrap_err();
+            assert_eq!(e.line, 1);
+            assert!(
+                matches!(e.kind, ErrorKind::DataTooLarge { max_bits: 2048 }),
+                "{}: {}",
+                source,
+                e
+            );
+        }
+        assert!(matches!(
+            assemble(Version::V1, "#d 0`136").unwrap_err().kind,
+            ErrorKind::DataTooLarge { max_bits: 128 }
+        ));
+        assert_eq!(v3("#d 0`2048").len(), 256);
+    }
+
+    #[test]
+    fn labels() {
+        let source = "
+            start:
+                jmp ${end}
+            loop:
+            .inner:
+                jmp ${.inner}
+            end:
+            .inner:
+                jmp ${.inner}
+                #d start, loop
+        ";
+        assert_eq!(v3(source), [0xd0, 4, 0xd0, 2, 0xd0, 4, 0, 2]);
+        assert!(matches!(
+            v3_error("a:\na:"),
+            ErrorKind::DuplicateLabel(l) if l == "a"
+        ));
+        assert!(matches!(
+            v3_error("jmp ${nowhere}"),
+            ErrorKind::UnknownLabel(l) if l == "nowhere"
+        ));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(v3("#d 1\n#addr 4\n#d 2"), [1, 0, 0, 0, 2]);
+        assert_eq!(v3("#d 1\n#res 2\nend:\n#d end"), [1, 0, 0, 3]);
+        assert!(matches!(
+            v3_error("#d 1, 2\n#addr 1"),
+            ErrorKind::AddressBackwards { from: 2, to: 1 }
+        ));
+        assert!(matches!(
+            v3_error("#addr 257"),
+            ErrorKind::ProgramTooLarge { len: 257, max: 256 }
+        ));
+        assert!(matches!(
+            assemble(Version::V1, "#res 17").unwrap_err().kind,
+            ErrorKind::ProgramTooLarge { len: 17, max: 16 }
+        ));
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        let e = assemble(Version::V3, "nop\n\nfoo").unwrap_err();
+        assert_eq!(e.line, 3);
+        assert!(matches!(e.kind, ErrorKind::NoMatchingRule(_)));
+        assert!(matches!(v3_error("#foo"), ErrorKind::UnknownDirective(d) if d == "foo"));
+    }
+
+    #[test]
+    fn ruledef_is_skipped() {
+        let source = "
+            #ruledef
+            {
+                nop => 0x00 ; {
+            }
+            hlt
+        ";
+        assert_eq!(v3(source), [0xff]);
+    }
+
+    #[test]
+    fn include_and_once() {
+        let dir = write_files(
+            "include",
+            &[
+                ("main.S", "#include \"lib.S\"\n#include \"lib.S\"\n#d 2"),
+                ("lib.S", "#d 1"),
+                (
+                    "once.S",
+                    "#include \"lib_once.S\"\n#include \"lib_once.S\"\n#d 2",
+                ),
+                ("lib_once.S", "#once\n#d 1"),
+            ],
+        );
+        assert_eq!(
+            assemble_file(Version::V3, dir.join("main.S")).unwrap(),
+            [1, 1, 2]
+        );
+        assert_eq!(
+            assemble_file(Version::V3, dir.join("once.S")).unwrap(),
+            [1, 2]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("a.S", "#d 1\n#include \"b.S\""),
+                ("b.S", "#include \"a.S\""),
+                ("self.S", "#include \"self.S\""),
+                ("a_once.S", "#once\n#d 1\n#include \"b_once.S\""),
+                ("b_once.S", "#include \"a_once.S\"\n#d 2"),
+            ],
+        );
+        let e = assemble_file(Version::V3, dir.join("a.S")).unwrap_err();
+        assert_eq!(e.file, Some(dir.join("b.S")));
+        assert_eq!(e.line, 1);
+        assert!(matches!(e.kind, ErrorKind::IncludeCycle(_)));
+        assert!(matches!(
+            assemble_file(Version::V3, dir.join("self.S"))
+                .unwrap_err()
+                .kind,
+            ErrorKind::IncludeCycle(_)
+        ));
+        // `#once` makes a cycle harmless
+        assert_eq!(
+            assemble_file(Version::V3, dir.join("a_once.S")).unwrap(),
+            [1, 2]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_sources_skips_build_output() {
+        let dir = write_files("find", &[]);
+        for file in [
+            "b.S",
+            "a.s",
+            "notes.txt",
+            "sub/c.S",
+            "target/d.S",
+            ".git/e.S",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let mut sources = Vec::new();
+        find_sources(&dir, &mut sources).unwrap();
+        assert_eq!(
+            sources,
+            [dir.join("a.s"), dir.join("b.S"), dir.join("sub/c.S")]
+        );
+        // A file is added whatever it's called
+        find_sources(&dir.join("target/d.S"), &mut sources).unwrap();
+        assert_eq!(sources.last(), Some(&dir.join("target/d.S")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn v1_operands() {
+        assert_eq!(
+            assemble(Version::V1, "ldav 12\naddm 0xf\ntxb\nout\nhlt").unwrap(),
+            [0x1c, 0x5f, 0x40, 0xe0, 0xf0]
+        );
+        assert!(matches!(
+            assemble(Version::V1, "ldav 16").unwrap_err().kind,
+            ErrorKind::OutOfRange { value: 16, bits: 4 }
+        ));
+        // Addresses are bare in v1 and v2
+        assert!(matches!(
+            assemble(Version::V1, "jmp $1").unwrap_err().kind,
+            ErrorKind::NoMatchingRule(_)
+        ));
+    }
+
+    #[test]
+    fn v2_operands() {
+        assert_eq!(
+            assemble_file(Version::V2, "../asm/v2/fibonacci.S").unwrap(),
+            [0x2a, 0x40, 0x2b, 0x3a, 0xe0, 0x50, 0x3b, 0xd9, 0xb0, 0xf0, 0, 1]
+        );
+        assert_eq!(
+            assemble(Version::V2, "addv 1\nsubv -1\nnop").unwrap(),
+            [0x61, 0x9f, 0x00]
+        );
+    }
+
+    #[test]
+    fn v3_operands() {
+        assert_eq!(v3("mov %a 0"), [0x02, 0x00]);
+        assert_eq!(v3("mov %a $0x10"), [0x03, 0x10]);
+        assert_eq!(v3("mov $0x10, %b"), [0x08, 0x10]);
+        assert_eq!(v3("mov $1 $2"), [0x0a, 1, 2]);
+        assert_eq!(v3("MOV %A %B"), [0x01]);
+        assert_eq!(v3("add 1 2"), [0x14, 1, 2]);
+        assert!(matches!(v3_error("mov %a"), ErrorKind::NoMatchingRule(_)));
+        assert!(matches!(v3_error("mov %c 1"), ErrorKind::Syntax(_)));
+        assert!(matches!(
+            v3_error("mov %a 0x100"),
+            ErrorKind::OutOfRange {
+                value: 256,
+                bits: 8
+            }
+        ));
+    }
+}
@@ -0,0 +1,631 @@
+//! An interactive debugger for any `Machine`
+
+use puttpc_emu::{
+    disasm, Budget, History, Limits, Machine, MachineError, Outcome, Register, Reset, Symbols,
+};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    error::Error,
+    hash::Hash,
+    io::{self, BufRead, Write},
+};
+
+/// The most microsteps `continue` runs when --max-steps isn't given
+const CONTINUE_STEPS: u64 = 1_000_000;
+
+const HELP: &str = "\
+Commands:
+  s, step [n]               Perform n microsteps (default 1)
+  n, next [n]               Perform n instructions (default 1)
+  c, continue [n]           Run until a breakpoint, watchpoint or halt, or for n microsteps
+                            (default --max-steps, or 1000000)
+  rs, reverse-step [n]      Undo n microsteps (default 1)
+  rn, reverse-next [n]      Undo n instructions (default 1)
+  rw, reverse-write <addr>  Run back to just before the last write to a memory cell
+  ro, reverse-output        Run back to just before the last output
+  b, break <addr>           Break when the counter reaches addr at the start of an instruction
+  w, watch <addr|reg>       Break when a memory cell or register changes
+  delete <addr|reg>         Remove a breakpoint or watchpoint
+  info                      List breakpoints and watchpoints
+  p, print [addr|reg]       Print the whole state, a memory cell or a register
+  set <addr|reg> <value>    Change a memory cell or register
+  reset [power]             Reset the machine, also clearing memory for a power-on reset
+  d, disasm [addr] [n]      Disassemble n instructions around addr (default the counter)
+  h, help                   Show this message
+  q, quit                   Exit the debugger
+Registers are a, b, counter, output, ramaddress and instruction. Numbers may be 0x or 0b prefixed.
+Addresses may also be labels from --symbols, like `loop`.";
+
+/// Something whose value can be watched or edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Location {
+    Register(usize),
+    Memory(usize),
+}
+
+impl Location {
+    fn parse(s: &str, symbols: &Symbols) -> Option<Self> {
+        (0..u8::MAX)
+            .map_while(|i| Register::try_from(i).ok())
+            .find(|r| format!("{:?}", r).eq_ignore_ascii_case(s))
+            .map(|r| Self::Register(r as usize))
+            .or_else(|| parse_address(s, symbols).map(Self::Memory))
+    }
+
+    fn get(self, machine: &impl Machine) -> Option<u8> {
+        match self {
+            Self::Register(r) => machine.regs().get(r).copied(),
+            Self::Memory(a) => machine.memory().get(a).copied(),
+        }
+    }
+
+    fn get_mut(self, machine: &mut impl Machine) -> Option<&mut u8> {
+        match self {
+            Self::Register(r) => machine.regs_mut().get_mut(r),
+            Self::Memory(a) => machine.memory_mut().get_mut(a),
+        }
+    }
+
+    fn name(self, symbols: &Symbols) -> String {
+        match self {
+            Self::Register(r) => {
+                let r = u8::try_from(r)
+                    .ok()
+                    .and_then(|r| Register::try_from(r).ok());
+                format!("{:?}", r.expect("only valid registers are parsed"))
+            }
+            Self::Memory(a) => match symbols.describe(a) {
+                Some(label) => format!("memory[0x{:02x}] ({})", a, label),
+                None => format!("memory[0x{:02x}]", a),
+            },
+        }
+    }
+}
+
+/// An address as a number, a label, or a label plus a number like `data+1`
+fn parse_address(s: &str, symbols: &Symbols) -> Option<usize> {
+    if let Some(n) = parse_number(&s.to_lowercase()) {
+        return Some(n);
+    }
+    match s.split_once('+') {
+        Some((label, offset)) => {
+            Some(symbols.address_of(label)? + parse_number(&offset.to_lowercase())?)
+        }
+        None => symbols.address_of(s),
+    }
+}
+
+/// An address in hex, followed by where it is relative to a label if there is one
+fn address_name(address: usize, symbols: &Symbols) -> String {
+    match symbols.describe(address) {
+        Some(label) => format!("0x{:02x} ({})", address, label),
+        None => format!("0x{:02x}", address),
+    }
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        usize::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// A command typed at the prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Step(u64),
+    Next(u64),
+    Continue(Option<u64>),
+    ReverseStep(usize),
+    ReverseNext(usize),
+    ReverseWrite(usize),
+    ReverseOutput,
+    Break(usize),
+    Watch(Location),
+    Delete(Location),
+    Info,
+    Print(Option<Location>),
+    Set(Location, u8),
+    Reset(Reset),
+    Disasm(Option<usize>, usize),
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parse a line, which is `None` if it's blank
+    fn parse(line: &str, symbols: &Symbols) -> Result<Option<Self>, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let number = |i: usize| -> Result<Option<usize>, String> {
+            words
+                .get(i)
+                .map(|w| parse_number(w).ok_or_else(|| format!("invalid number `{}`", w)))
+                .transpose()
+        };
+        let count = |i: usize| -> Result<u64, String> { Ok(number(i)?.map_or(1, |n| n as u64)) };
+        let address = |i: usize| -> Result<Option<usize>, String> {
+            words
+                .get(i)
+                .map(|w| {
+                    parse_address(w, symbols).ok_or_else(|| format!("invalid address `{}`", w))
+                })
+                .transpose()
+        };
+        let location = |i: usize| -> Result<Location, String> {
+            let w = words.get(i).ok_or("expected an address or register")?;
+            Location::parse(w, symbols)
+                .ok_or_else(|| format!("invalid address or register `{}`", w))
+        };
+
+        Ok(Some(match words.first().copied().unwrap_or_default() {
+            "" => return Ok(None),
+            "s" | "step" => Self::Step(count(1)?),
+            "n" | "next" => Self::Next(count(1)?),
+            "c" | "continue" => Self::Continue(number(1)?.map(|n| n as u64)),
+            "rs" | "reverse-step" => Self::ReverseStep(number(1)?.unwrap_or(1)),
+            "rn" | "reverse-next" => Self::ReverseNext(number(1)?.unwrap_or(1)),
+            "rw" | "reverse-write" => Self::ReverseWrite(address(1)?.ok_or("expected an address")?),
+            "ro" | "reverse-output" => Self::ReverseOutput,
+            "b" | "break" => Self::Break(address(1)?.ok_or("expected an address")?),
+            "w" | "watch" => Self::Watch(location(1)?),
+            "delete" => Self::Delete(location(1)?),
+            "info" => Self::Info,
+            "p" | "print" => Self::Print(words.get(1).map(|_| location(1)).transpose()?),
+            "set" => {
+                let value = number(2)?.ok_or("expected a value")?;
+                let value = u8::try_from(value).map_err(|_| "value doesn't fit in a byte")?;
+                Self::Set(location(1)?, value)
+            }
+            "reset" => Self::Reset(match words.get(1).copied() {
+                None => Reset::Soft,
+                Some("power") => Reset::PowerOn,
+                Some(w) => return Err(format!("unknown reset `{}`", w)),
+            }),
+            "d" | "disasm" => Self::Disasm(address(1)?, number(2)?.unwrap_or(10).max(1)),
+            "h" | "help" => Self::Help,
+            "q" | "quit" => Self::Quit,
+            c => return Err(format!("unknown command `{}`, try `help`", c)),
+        }))
+    }
+}
+
+/// Why the machine stopped running
+enum Stop {
+    Halted,
+    Breakpoint(usize),
+    Watchpoint(Location, u8, u8),
+}
+
+struct Debugger<M: Machine> {
+    history: History<M>,
+    symbols: Symbols,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<Location>,
+    /// The limits of `continue`
+    limits: Limits,
+}
+
+impl<M: Machine + Clone + Eq + Hash> Debugger<M> {
+    fn new(machine: M, symbols: Symbols, limits: Limits) -> Self {
+        Self {
+            history: History::new(machine),
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            limits: Limits {
+                max_steps: limits.max_steps.or(Some(CONTINUE_STEPS)),
+                ..limits
+            },
+        }
+    }
+
+    fn counter(&self) -> usize {
+        usize::from(self.history.machine().regs()[Register::Counter as usize])
+    }
+
+    fn watched(&self) -> Vec<(Location, Option<u8>)> {
+        self.watchpoints
+            .iter()
+            .map(|w| (*w, w.get(self.history.machine())))
+            .collect()
+    }
+
+    /// Perform a single microstep, printing any output
+    fn step(&mut self) -> Result<Option<Stop>, MachineError> {
+        let before = self.watched();
+        if let Some(out) = self.history.step()? {
+            println!("Output: 0x{:02x}", out);
+        }
+
+        if let Some((w, old, new)) = before
+            .into_iter()
+            .map(|(w, old)| (w, old, w.get(self.history.machine())))
+            .find(|(_, old, new)| old != new)
+        {
+            let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+            return Ok(Some(Stop::Watchpoint(w, old, new)));
+        }
+        if self.history.machine().is_halted() {
+            return Ok(Some(Stop::Halted));
+        }
+        if self.history.machine().micro() == 0 && self.breakpoints.contains(&self.counter()) {
+            return Ok(Some(Stop::Breakpoint(self.counter())));
+        }
+        Ok(None)
+    }
+
+    /// Run within `limits`, stopping early if something is hit
+    ///
+    /// Returns how many steps were run, and why they stopped if it was because of the limits.
+    fn run(&mut self, limits: Limits) -> Result<(u64, Option<Outcome>), MachineError> {
+        if self.history.machine().is_halted() {
+            println!("The machine has halted");
+            return Ok((0, None));
+        }
+        let mut budget = Budget::new(limits);
+        let outcome = loop {
+            if let Some(outcome) = budget.check(self.history.machine()) {
+                break Some(outcome);
+            }
+            let stop = self.step()?;
+            budget.count(self.history.machine());
+            match stop {
+                Some(Stop::Halted) => {
+                    println!("Halted");
+                    break None;
+                }
+                Some(Stop::Breakpoint(addr)) => {
+                    println!("Breakpoint at {}", address_name(addr, &self.symbols));
+                    break None;
+                }
+                Some(Stop::Watchpoint(w, old, new)) => {
+                    let name = w.name(&self.symbols);
+                    println!("{} changed from 0x{:02x} to 0x{:02x}", name, old, new);
+                    break None;
+                }
+                None => {}
+            }
+        };
+        Ok((budget.steps, outcome))
+    }
+
+    fn run_continue(&mut self, steps: Option<u64>) -> Result<(), MachineError> {
+        let limits = Limits {
+            max_steps: steps.or(self.limits.max_steps),
+            ..self.limits
+        };
+        match self.run(limits)? {
+            (steps, Some(Outcome::BudgetExhausted)) => println!(
+                "Stopped after {} steps without halting, `continue <n>` runs for longer",
+                steps
+            ),
+            (_, Some(Outcome::Looping)) => println!("The machine is stuck in a loop"),
+            _ => {}
+        }
+        self.print_position();
+        Ok(())
+    }
+
+    /// Undo up to `count` microsteps or instructions
+    fn reverse(&mut self, count: usize, instructions: bool) {
+        for _ in 0..count {
+            let undone = if instructions {
+                self.history.step_back_instruction()
+            } else {
+                self.history.step_back()
+            };
+            if !undone {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+        }
+        self.print_position();
+    }
+
+    fn print_position(&self) {
+        let counter = self
+            .counter()
+            .min(self.history.machine().memory().len() - 1);
+        let line = disasm::disassemble_at_with_symbols(
+            self.history.machine().version(),
+            self.history.machine().memory(),
+            counter,
+            &self.symbols,
+        );
+        let micro = self.history.machine().micro();
+        match self.symbols.describe(counter) {
+            Some(label) => println!(
+                "micro {} | {} | {}",
+                micro,
+                label,
+                line.with_source(&self.symbols)
+            ),
+            None => println!("micro {} | {}", micro, line.with_source(&self.symbols)),
+        }
+    }
+
+    fn disassemble(&self, around: usize, count: usize) {
+        let version = self.history.machine().version();
+        let memory = self.history.machine().memory();
+        let around = around.min(memory.len() - 1);
+
+        // Decoding from the start keeps the lines before `around` aligned with real instructions
+        let before: Vec<_> =
+            disasm::disassemble_with_symbols(version, &memory[..around], &self.symbols)
+                .into_iter()
+                .filter(|l| l.address + l.bytes.len() <= around)
+                .collect();
+        let before = &before[before.len().saturating_sub(count / 2)..];
+
+        let mut address = around;
+        let mut after = Vec::new();
+        while address < memory.len() && after.len() < count - before.len() {
+            let line = disasm::disassemble_at_with_symbols(version, memory, address, &self.symbols);
+            address += line.bytes.len();
+            after.push(line);
+        }
+
+        for line in before.iter().chain(&after) {
+            for label in self.symbols.labels_at(line.address) {
+                println!("{}:", label);
+            }
+            let marker = if line.address == self.counter() {
+                "=>"
+            } else if self.breakpoints.contains(&line.address) {
+                " *"
+            } else {
+                "  "
+            };
+            println!("{} {}", marker, line.with_source(&self.symbols));
+        }
+    }
+
+    /// Handle a single command, returning whether to quit
+    fn command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let command = match Command::parse(line, &self.symbols)? {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+
+        match command {
+            Command::Step(n) => {
+                self.run(Limits {
+                    max_steps: Some(n),
+                    ..Limits::default()
+                })?;
+                self.print_position();
+            }
+            Command::Next(n) => {
+                self.run(Limits {
+                    max_instructions: Some(n),
+                    ..Limits::default()
+                })?;
+                self.print_position();
+            }
+            Command::Continue(steps) => self.run_continue(steps)?,
+            Command::ReverseStep(n) => self.reverse(n, false),
+            Command::ReverseNext(n) => self.reverse(n, true),
+            Command::ReverseWrite(addr) => {
+                if !self.history.back_to_write(addr) {
+                    println!("No write to 0x{:02x} was recorded", addr);
+                }
+                self.print_position();
+            }
+            Command::ReverseOutput => {
+                if !self.history.back_to_output() {
+                    println!("No output was recorded");
+                }
+                self.print_position();
+            }
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+            }
+            Command::Watch(l) => {
+                l.get(self.history.machine()).ok_or("out of range")?;
+                self.watchpoints.insert(l);
+            }
+            Command::Delete(l) => {
+                let removed = match l {
+                    Location::Memory(a) => self.breakpoints.remove(&a),
+                    Location::Register(_) => false,
+                };
+                if !(self.watchpoints.remove(&l) || removed) {
+                    return Err("no breakpoint or watchpoint there".into());
+                }
+            }
+            Command::Info => {
+                for b in &self.breakpoints {
+                    println!("Breakpoint at {}", address_name(*b, &self.symbols));
+                }
+                for w in &self.watchpoints {
+                    println!("Watching {}", w.name(&self.symbols));
+                }
+            }
+            Command::Print(None) => println!("{}", self.history.machine()),
+            Command::Print(Some(l)) => {
+                let v = l.get(self.history.machine()).ok_or("out of range")?;
+                println!("{} = {v} (0x{v:02x}, 0b{v:08b})", l.name(&self.symbols));
+            }
+            Command::Set(l, value) => {
+                *l.get_mut(self.history.machine_mut())
+                    .ok_or("out of range")? = value;
+            }
+            Command::Reset(kind) => {
+                self.history.machine_mut().reset(kind);
+                self.history.clear();
+                self.print_position();
+            }
+            Command::Disasm(around, count) => {
+                let around = around.unwrap_or_else(|| self.counter());
+                self.disassemble(around, count);
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Quit => return Ok(true),
+        }
+
+        Ok(false)
+    }
+}
+
+/// Run an interactive debugger on stdin and stdout until the user quits
+///
+/// `continue` runs within `limits`, or for a million steps if they don't limit the steps.
+pub fn debug(
+    machine: impl Machine + Clone + Eq + Hash,
+    symbols: Symbols,
+    limits: Limits,
+) -> Result<(), Box<dyn Error>> {
+    let mut debugger = Debugger::new(machine, symbols, limits);
+
+    println!("Type `help` for a list of commands");
+    debugger.print_position();
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(puttpc) ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        match debugger.command(&line) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use puttpc_emu::{asm, v2, Version};
+
+    /// The Fibonacci program and its labels
+    fn fibonacci() -> Debugger<v2::PuttPc> {
+        let (image, symbols) =
+            asm::assemble_file_with_symbols(Version::V2, "../asm/v2/fibonacci.S").unwrap();
+        Debugger::new(
+            v2::PuttPc::with_input(&image).unwrap(),
+            symbols,
+            Limits::default(),
+        )
+    }
+
+    #[test]
+    fn parses_commands() {
+        let symbols = fibonacci().symbols;
+        let data = symbols.address_of("data").unwrap();
+        let parse = |line| Command::parse(line, &symbols);
+        let ok = |line| parse(line).unwrap().unwrap();
+
+        assert_eq!(parse("  "), Ok(None));
+        assert_eq!(ok("s"), Command::Step(1));
+        assert_eq!(ok("next 0x10"), Command::Next(16));
+        assert_eq!(ok("c"), Command::Continue(None));
+        assert_eq!(ok("continue 500"), Command::Continue(Some(500)));
+        assert_eq!(ok("rn 2"), Command::ReverseNext(2));
+        assert_eq!(ok("b data+1"), Command::Break(data + 1));
+        assert_eq!(ok("rw data"), Command::ReverseWrite(data));
+        assert_eq!(
+            ok("watch A"),
+            Command::Watch(Location::Register(Register::A as usize))
+        );
+        assert_eq!(ok("w 0b11"), Command::Watch(Location::Memory(3)));
+        assert_eq!(
+            ok("set counter 255"),
+            Command::Set(Location::Register(Register::Counter as usize), 255)
+        );
+        assert_eq!(ok("print"), Command::Print(None));
+        assert_eq!(ok("reset power"), Command::Reset(Reset::PowerOn));
+        assert_eq!(ok("d loop 4"), Command::Disasm(Some(0), 4));
+        assert_eq!(ok("d"), Command::Disasm(None, 10));
+
+        for line in [
+            "s x",
+            "b",
+            "b nowhere",
+            "w flags",
+            "set a 256",
+            "set a",
+            "reset hard",
+            "jump",
+        ] {
+            assert!(parse(line).is_err(), "`{}` was accepted", line);
+        }
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut debugger = fibonacci();
+        let end = debugger.symbols.address_of("end").unwrap();
+        debugger.command(&format!("b {}", end)).unwrap();
+        debugger.command("c").unwrap();
+        let machine = debugger.history.machine();
+        assert!(!machine.is_halted());
+        assert_eq!((debugger.counter(), machine.micro()), (end, 0));
+
+        debugger.command("delete end").unwrap();
+        debugger.command("c").unwrap();
+        assert!(debugger.history.machine().is_halted());
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        let mut debugger = fibonacci();
+        let data = debugger.symbols.address_of("data").unwrap();
+        debugger.command("w data").unwrap();
+        debugger.command("w output").unwrap();
+
+        // `sta data` writes the second number over the first, before it's output
+        debugger.command("c").unwrap();
+        assert_eq!(debugger.history.machine().memory()[data], 1);
+        assert_eq!(
+            debugger.history.machine().regs()[Register::Output as usize],
+            0
+        );
+        debugger.command("c").unwrap();
+        assert_eq!(
+            debugger.history.machine().regs()[Register::Output as usize],
+            1
+        );
+        assert!(!debugger.history.machine().is_halted());
+    }
+
+    #[test]
+    fn continue_stops_within_its_limits() {
+        // jmp 0
+        let machine = v2::PuttPc::with_input(&[0xb0]).unwrap();
+        let mut debugger = Debugger::new(
+            machine.clone(),
+            Symbols::default(),
+            Limits {
+                max_steps: Some(50),
+                ..Limits::default()
+            },
+        );
+        debugger.command("c").unwrap();
+        assert_eq!(debugger.history.len(), 50);
+        debugger.command("c 7").unwrap();
+        assert_eq!(debugger.history.len(), 57);
+
+        let mut debugger = Debugger::new(
+            machine,
+            Symbols::default(),
+            Limits {
+                detect_loops: true,
+                ..Limits::default()
+            },
+        );
+        debugger.command("c").unwrap();
+        assert!(debugger.history.len() < 10);
+    }
+}
@@ -0,0 +1,226 @@
+//! A disassembler producing the syntax of `asm/v*/ruledef.S`
+
+use crate::{
+    isa::{self, Operand},
+    Symbols, Version,
+};
+use std::fmt;
+
+/// A single disassembled instruction (or data byte)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{:02x}: {:<8}  {}", self.address, bytes, self.text)
+    }
+}
+
+impl Line {
+    /// The line followed by the source line that emitted it as a comment, if that's known
+    #[must_use]
+    pub fn with_source(&self, symbols: &Symbols) -> String {
+        match symbols.source_at(self.address) {
+            Some(source) => format!("{:<32}  ; {}", self.to_string(), source),
+            None => self.to_string(),
+        }
+    }
+}
+
+/// Disassemble a whole memory image
+#[must_use]
+pub fn disassemble(version: Version, memory: &[u8]) -> Vec<Line> {
+    disassemble_with_symbols(version, memory, &Symbols::default())
+}
+
+/// Disassemble a whole memory image, writing addresses as the labels in `symbols`
+#[must_use]
+pub fn disassemble_with_symbols(version: Version, memory: &[u8], symbols: &Symbols) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut address = 0;
+    while address < memory.len() {
+        let line = disassemble_at_with_symbols(version, memory, address, symbols);
+        address += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Disassemble the single instruction at `address`
+///
+/// Bytes that aren't a valid instruction are shown as a `#d` directive.
+///
+/// # Panics
+///
+/// Panics if `address` is outside of `memory`.
+#[must_use]
+pub fn disassemble_at(version: Version, memory: &[u8], address: usize) -> Line {
+    disassemble_at_with_symbols(version, memory, address, &Symbols::default())
+}
+
+/// Disassemble the single instruction at `address`, writing addresses as the labels in `symbols`
+///
+/// # Panics
+///
+/// Panics if `address` is outside of `memory`.
+#[must_use]
+pub fn disassemble_at_with_symbols(
+    version: Version,
+    memory: &[u8],
+    address: usize,
+    symbols: &Symbols,
+) -> Line {
+    let byte = memory[address];
+    let data = || Line {
+        address,
+        bytes: vec![byte],
+        text: format!("#d 0x{:02x}", byte),
+    };
+
+    match version {
+        Version::V1 | Version::V2 => {
+            let (mnemonic, operands) = match isa::syntax(version, byte >> 4) {
+                Some(s) => s,
+                None => return data(),
+            };
+            let operand = byte & 0xF;
+            let text = match operands.first() {
+                Some(o) => format!(
+                    "{} {}",
+                    mnemonic,
+                    format_operand(version, *o, operand, symbols)
+                ),
+                // ruledef.S encodes a zero operand, so anything else can't be reassembled
+                None if operand != 0 => return data(),
+                None => mnemonic.to_string(),
+            };
+            Line {
+                address,
+                bytes: vec![byte],
+                text,
+            }
+        }
+        Version::V3 => {
+            let (mnemonic, operands) = match isa::syntax(version, byte) {
+                Some(s) => s,
+                None => return data(),
+            };
+            let len = 1 + operands
+                .iter()
+                .filter(|o| matches!(o, Operand::Value | Operand::Address))
+                .count();
+            let bytes = match memory.get(address..address + len) {
+                Some(bytes) => bytes,
+                None => return data(),
+            };
+
+            let mut text = mnemonic.to_string();
+            let mut values = bytes[1..].iter();
+            for o in operands {
+                let value = match o {
+                    Operand::A | Operand::B => 0,
+                    Operand::Value | Operand::Address => *values.next().unwrap(),
+                };
+                text.push(' ');
+                text.push_str(&format_operand(version, *o, value, symbols));
+            }
+            Line {
+                address,
+                bytes: bytes.to_vec(),
+                text,
+            }
+        }
+    }
+}
+
+fn format_operand(version: Version, operand: Operand, value: u8, symbols: &Symbols) -> String {
+    let label = || symbols.describe(usize::from(value));
+    match (version, operand) {
+        (_, Operand::A) => "%a".to_string(),
+        (_, Operand::B) => "%b".to_string(),
+        (_, Operand::Value) => value.to_string(),
+        (Version::V1 | Version::V2, Operand::Address) => {
+            label().unwrap_or_else(|| format!("0x{:x}", value))
+        }
+        (Version::V3, Operand::Address) => {
+            format!("${}", label().unwrap_or_else(|| format!("0x{:02x}", value)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// Assemble every program in `asm/`, then check its disassembly assembles to the same bytes
+    #[test]
+    fn round_trips_through_the_assembler() {
+        let mut sources = Vec::new();
+        asm::find_sources("../asm".as_ref(), &mut sources).unwrap();
+        let mut versions = Vec::new();
+        for source in sources {
+            let version = match asm::version_of(&source) {
+                Some(version) if source.file_stem().unwrap() != "ruledef" => version,
+                _ => continue,
+            };
+            let image = asm::assemble_file(version, &source).unwrap();
+            let text = disassemble(version, &image)
+                .iter()
+                .map(|line| line.text.clone())
+                .collect::<Vec<_>>()
+                .join("\n");
+            assert_eq!(
+                asm::assemble(version, &text).unwrap(),
+                image,
+                "{}:\n{}",
+                source.display(),
+                text
+            );
+            versions.push(version);
+        }
+        for version in Version::ALL {
+            assert!(versions.contains(&version), "no programs for {:?}", version);
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let text = |version, memory: &[u8]| disassemble_at(version, memory, 0).text;
+        assert_eq!(text(Version::V1, &[0x1c]), "ldav 12");
+        assert_eq!(text(Version::V2, &[0x7f]), "addm 0xf");
+        assert_eq!(text(Version::V3, &[0x17, 0x80, 0x01]), "add $0x80 1");
+        assert_eq!(text(Version::V3, &[0x10]), "add %a %b");
+    }
+
+    #[test]
+    fn invalid_opcodes_are_data() {
+        let line = disassemble_at(Version::V1, &[0xa0], 0);
+        assert_eq!((line.bytes, line.text), (vec![0xa0], "#d 0xa0".to_string()));
+        assert_eq!(disassemble_at(Version::V3, &[0x0b], 0).text, "#d 0x0b");
+        // An operand on an instruction that doesn't take one can't be reassembled
+        assert_eq!(disassemble_at(Version::V2, &[0xe1], 0).text, "#d 0xe1");
+    }
+
+    #[test]
+    fn truncated_operands_are_data() {
+        let memory = [0x00, 0x09, 0x80];
+        let lines = disassemble(Version::V3, &memory);
+        let texts: Vec<_> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["nop", "#d 0x09", "#d 0x80"]);
+        assert_eq!(
+            lines.iter().map(|l| l.bytes.len()).sum::<usize>(),
+            memory.len()
+        );
+    }
+}
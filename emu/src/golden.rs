@@ -0,0 +1,246 @@
+//! Golden-output tests: assembly programs annotated with the output they should produce
+//!
+//! A program opts in with `; expect:` comments listing its output bytes in order, such as
+//! `; expect: 0x00 0x01 0x01 0x02`. Long outputs can be split over several of these comments,
+//! and an empty `; expect:` checks that a program outputs nothing.
+
+use crate::{asm, v1, v2, v3, Limits, Machine, MachineError, Microcode, Outcome, Run, Version};
+use std::{fmt, path::Path};
+
+/// Why a golden-output test failed
+#[derive(Debug)]
+pub enum Failure {
+    /// An `; expect:` comment has something that isn't a byte in it, on this 1-based line
+    BadAnnotation {
+        line: usize,
+        text: String,
+    },
+    Assemble(asm::Error),
+    Fault(MachineError),
+    /// The program didn't halt within its budget of steps
+    OutOfSteps {
+        steps: u64,
+        expected: Vec<u8>,
+        output: Vec<u8>,
+    },
+    WrongOutput {
+        expected: Vec<u8>,
+        output: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadAnnotation { line, text } => {
+                write!(f, "line {}: `{}` in `; expect:` isn't a byte", line, text)
+            }
+            Self::Assemble(e) => write!(f, "could not assemble: {}", e),
+            Self::Fault(e) => write!(f, "machine faulted: {}", e),
+            Self::OutOfSteps {
+                steps,
+                expected,
+                output,
+            } => {
+                writeln!(f, "did not halt within {} steps", steps)?;
+                diff(f, expected, output)
+            }
+            Self::WrongOutput { expected, output } => {
+                match expected.iter().zip(output).position(|(e, o)| e != o) {
+                    Some(i) => writeln!(f, "output differs from byte {}", i)?,
+                    None => writeln!(
+                        f,
+                        "expected {} bytes of output, got {}",
+                        expected.len(),
+                        output.len()
+                    )?,
+                }
+                diff(f, expected, output)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Assemble(e) => Some(e),
+            Self::Fault(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Write the expected and actual output one above the other, marking where they first differ
+fn diff(f: &mut fmt::Formatter<'_>, expected: &[u8], output: &[u8]) -> fmt::Result {
+    let bytes = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("0x{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    writeln!(f, "  expected: {}", bytes(expected))?;
+    write!(f, "  actual:   {}", bytes(output))?;
+    let first = expected
+        .iter()
+        .zip(output)
+        .position(|(e, o)| e != o)
+        .or_else(|| (expected.len() != output.len()).then(|| expected.len().min(output.len())));
+    if let Some(i) = first {
+        write!(f, "\n            {}^", " ".repeat(i * 5))?;
+    }
+    Ok(())
+}
+
+/// The output a program's `; expect:` comments say it produces, or `None` if it has none
+///
+/// # Errors
+///
+/// Returns an error if a comment has something that isn't a byte in it.
+pub fn expected_output(source: &str) -> Result<Option<Vec<u8>>, Failure> {
+    let mut expected: Option<Vec<u8>> = None;
+    for (i, line) in source.lines().enumerate() {
+        let bytes = match line.trim().strip_prefix(';') {
+            Some(comment) => match comment.trim().strip_prefix("expect:") {
+                Some(bytes) => bytes,
+                None => continue,
+            },
+            None => continue,
+        };
+        let expected = expected.get_or_insert_with(Vec::new);
+        for text in bytes.split_whitespace() {
+            let byte = parse_byte(text).ok_or_else(|| Failure::BadAnnotation {
+                line: i + 1,
+                text: text.to_string(),
+            })?;
+            expected.push(byte);
+        }
+    }
+    Ok(expected)
+}
+
+/// Assemble and run a program, checking that it halts with the expected output
+///
+/// The machine runs `microcode` if it's given, and faults on bus contention if `check_bus` is set.
+///
+/// # Errors
+///
+/// Returns the reason the test failed.
+pub fn check(
+    path: &Path,
+    version: Version,
+    expected: &[u8],
+    max_steps: u64,
+    microcode: Option<&Microcode>,
+    check_bus: bool,
+) -> Result<(), Failure> {
+    let image = asm::assemble_file(version, path).map_err(Failure::Assemble)?;
+    let limits = Limits {
+        max_steps: Some(max_steps),
+        ..Limits::default()
+    };
+    let run = match version {
+        Version::V1 => run(v1::PuttPc::with_input(&image), limits, microcode, check_bus),
+        Version::V2 => run(v2::PuttPc::with_input(&image), limits, microcode, check_bus),
+        Version::V3 => run(v3::PuttPc::with_input(&image), limits, microcode, check_bus),
+    }
+    .map_err(Failure::Fault)?;
+
+    match run.outcome {
+        Outcome::Halted if run.output == expected => Ok(()),
+        Outcome::Halted => Err(Failure::WrongOutput {
+            expected: expected.to_vec(),
+            output: run.output,
+        }),
+        Outcome::BudgetExhausted | Outcome::Looping => Err(Failure::OutOfSteps {
+            steps: run.steps,
+            expected: expected.to_vec(),
+            output: run.output,
+        }),
+    }
+}
+
+fn run<M>(
+    machine: Result<M, MachineError>,
+    limits: Limits,
+    microcode: Option<&Microcode>,
+    check_bus: bool,
+) -> Result<Run<u8>, MachineError>
+where
+    M: Machine<Output = u8> + Clone + Eq + std::hash::Hash,
+{
+    let mut machine = machine?;
+    machine.set_check_bus(check_bus);
+    if let Some(microcode) = microcode {
+        machine.set_microcode(microcode.clone())?;
+    }
+    machine.run_bounded(limits)
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_output_annotations() {
+        assert_eq!(expected_output("out\nhlt ; 1 2").unwrap(), None);
+        // Code with a trailing comment isn't an annotation
+        assert_eq!(expected_output("out ; expect: 1").unwrap(), None);
+        assert_eq!(expected_output("; expect:").unwrap(), Some(Vec::new()));
+        assert_eq!(
+            expected_output("  ; expect: 0x01 0b10 3\nout\n;expect:255\n; expected: 9").unwrap(),
+            Some(vec![1, 2, 3, 255])
+        );
+        for (source, bad_line, bad_text) in [
+            ("; expect: 256", 1, "256"),
+            ("out\n; expect: 1, 2", 2, "1,"),
+            ("; expect: 0xfg", 1, "0xfg"),
+        ] {
+            match expected_output(source) {
+                Err(Failure::BadAnnotation { line, text }) => {
+                    assert_eq!((line, text.as_str()), (bad_line, bad_text), "{}", source);
+                }
+                result => panic!("{:?} from {}", result, source),
+            }
+        }
+    }
+
+    #[test]
+    fn check_uses_the_machine_configuration() {
+        let path = Path::new("../asm/v2/fibonacci.S");
+        let expected = [1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233];
+        check(path, Version::V2, &expected, 10_000, None, true).unwrap();
+
+        // `out` shows B instead of A
+        let text = Microcode::builtin(Version::V2)
+            .to_string()
+            .replace("0x0e 2: OUTPUT_IN | A_OUT", "0x0e 2: OUTPUT_IN | B_OUT");
+        let microcode = Microcode::parse(Version::V2, &text).unwrap();
+        assert!(matches!(
+            check(
+                path,
+                Version::V2,
+                &expected,
+                10_000,
+                Some(&microcode),
+                false
+            ),
+            Err(Failure::WrongOutput { .. })
+        ));
+        assert!(matches!(
+            check(path, Version::V2, &expected, 10, None, false),
+            Err(Failure::OutOfSteps { steps: 10, .. })
+        ));
+    }
+}
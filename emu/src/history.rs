@@ -0,0 +1,276 @@
+//! Reverse execution of a `Machine` through an undo log
+
+use crate::{Machine, MachineError};
+use std::collections::VecDeque;
+
+/// Everything a single step changed, with the values from before it
+#[derive(Debug, Clone)]
+struct Undo<L> {
+    regs: Vec<(usize, u8)>,
+    memory: Vec<(usize, u8)>,
+    latches: L,
+    output: bool,
+}
+
+/// A machine that records its steps so it can be run backwards
+///
+/// Only the most recent `limit` steps are kept, so a program that never halts can't use up memory.
+#[derive(Debug, Clone)]
+pub struct History<M: Machine> {
+    machine: M,
+    log: VecDeque<Undo<M::Latches>>,
+    limit: usize,
+    /// The registers and memory from before the current step, kept to save allocating each step
+    before: (Vec<u8>, Vec<u8>),
+}
+
+impl<M: Machine> History<M> {
+    /// The number of steps kept by `new`, which is a few hundred megabytes at most
+    pub const DEFAULT_LIMIT: usize = 1_000_000;
+
+    #[must_use]
+    pub fn new(machine: M) -> Self {
+        Self::with_limit(machine, Self::DEFAULT_LIMIT)
+    }
+
+    /// Record only the most recent `limit` steps
+    #[must_use]
+    pub fn with_limit(machine: M, limit: usize) -> Self {
+        Self {
+            machine,
+            log: VecDeque::new(),
+            limit,
+            before: (Vec::new(), Vec::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn machine(&self) -> &M {
+        &self.machine
+    }
+
+    /// The machine, mutably
+    ///
+    /// Changes made through this aren't recorded, so stepping back won't undo them.
+    pub fn machine_mut(&mut self) -> &mut M {
+        &mut self.machine
+    }
+
+    #[must_use]
+    pub fn into_inner(self) -> M {
+        self.machine
+    }
+
+    /// The number of steps that can be undone, at most the limit
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.log.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    /// Forget every recorded step, such as after resetting the machine
+    pub fn clear(&mut self) {
+        self.log.clear();
+    }
+
+    /// Perform one step of the machine, recording how to undo it
+    ///
+    /// A step that faults is recorded too, so the fault can be stepped back over. Once the limit
+    /// is reached, the oldest step is forgotten.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and halts the machine, if the step faults.
+    pub fn step(&mut self) -> Result<Option<M::Output>, MachineError> {
+        let (regs, memory) = &mut self.before;
+        regs.clear();
+        regs.extend_from_slice(self.machine.regs());
+        memory.clear();
+        memory.extend_from_slice(self.machine.memory());
+        let latches = self.machine.latches();
+
+        let out = self.machine.step();
+
+        let changed = |old: &[u8], new: &[u8]| -> Vec<(usize, u8)> {
+            old.iter()
+                .zip(new)
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(i, (old, _))| (i, *old))
+                .collect()
+        };
+        if self.limit == 0 {
+            return out;
+        }
+        if self.log.len() == self.limit {
+            self.log.pop_front();
+        }
+        self.log.push_back(Undo {
+            regs: changed(regs, self.machine.regs()),
+            memory: changed(memory, self.machine.memory()),
+            latches,
+            output: matches!(out, Ok(Some(_))),
+        });
+
+        out
+    }
+
+    /// Undo the last step, returning `false` if there was nothing to undo
+    pub fn step_back(&mut self) -> bool {
+        self.pop().is_some()
+    }
+
+    /// Undo steps until the start of the previous instruction
+    ///
+    /// Returns `false` if there was nothing to undo.
+    pub fn step_back_instruction(&mut self) -> bool {
+        if self.pop().is_none() {
+            return false;
+        }
+        while self.machine.micro() != 0 && self.pop().is_some() {}
+        true
+    }
+
+    /// Undo steps until just before the last write to the memory cell at `address`
+    ///
+    /// Returns `false`, having undone everything, if there was no such write.
+    pub fn back_to_write(&mut self, address: usize) -> bool {
+        while let Some(undo) = self.pop() {
+            if undo.memory.iter().any(|(a, _)| *a == address) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Undo steps until just before the last step that produced output
+    ///
+    /// Returns `false`, having undone everything, if there was no output.
+    pub fn back_to_output(&mut self) -> bool {
+        while let Some(undo) = self.pop() {
+            if undo.output {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn pop(&mut self) -> Option<Undo<M::Latches>> {
+        let undo = self.log.pop_back()?;
+        for (r, v) in &undo.regs {
+            self.machine.regs_mut()[*r] = *v;
+        }
+        for (a, v) in &undo.memory {
+            self.machine.memory_mut()[*a] = *v;
+        }
+        self.machine.set_latches(undo.latches.clone());
+        Some(undo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, v2, v3, Version};
+
+    /// The Fibonacci program, which writes memory and outputs
+    fn fibonacci() -> v2::PuttPc {
+        let image = asm::assemble_file(Version::V2, "../asm/v2/fibonacci.S").unwrap();
+        v2::PuttPc::with_input(&image).unwrap()
+    }
+
+    /// Step `steps` times, returning the machine from before each step
+    fn record(history: &mut History<v2::PuttPc>, steps: usize) -> Vec<v2::PuttPc> {
+        (0..steps)
+            .map(|_| {
+                let before = history.machine().clone();
+                history.step().unwrap();
+                before
+            })
+            .collect()
+    }
+
+    #[test]
+    fn step_back_restores_each_state() {
+        let mut history = History::new(fibonacci());
+        let states = record(&mut history, 200);
+        for state in states.iter().rev() {
+            assert!(history.step_back());
+            assert_eq!(history.machine(), state);
+        }
+        assert!(!history.step_back());
+        assert_eq!(history.machine(), &fibonacci());
+    }
+
+    #[test]
+    fn step_back_instruction() {
+        let mut history = History::new(fibonacci());
+        let states = record(&mut history, 12);
+        let starts: Vec<_> = (0..states.len())
+            .filter(|i| states[*i].micro() == 0)
+            .collect();
+        assert!(starts.len() > 2);
+        for start in starts.iter().rev() {
+            assert!(history.step_back_instruction());
+            assert_eq!(history.machine(), &states[*start]);
+        }
+        assert!(!history.step_back_instruction());
+    }
+
+    #[test]
+    fn back_to_write_and_output() {
+        let mut history = History::new(fibonacci());
+        let mut states = Vec::new();
+        let mut last_output = None;
+        for i in 0..100 {
+            states.push(history.machine().clone());
+            if history.step().unwrap().is_some() {
+                last_output = Some(i);
+            }
+        }
+        // The first instruction is `ldam data`
+        let data = usize::from(history.machine().memory()[0] & 0xf);
+        let last_write = states
+            .windows(2)
+            .rposition(|w| w[0].memory()[data] != w[1].memory()[data])
+            .unwrap();
+
+        let mut back = history.clone();
+        assert!(back.back_to_write(data));
+        assert_eq!(back.machine(), &states[last_write]);
+        // Nothing writes over the program
+        assert!(!back.back_to_write(0));
+        assert!(back.is_empty());
+
+        assert!(history.back_to_output());
+        assert_eq!(history.machine(), &states[last_output.unwrap()]);
+    }
+
+    #[test]
+    fn faults_can_be_stepped_back_over() {
+        // 0x0b isn't an instruction
+        let before = v3::PuttPc::with_input(&[0x0b]).unwrap();
+        let mut history = History::new(before.clone());
+        while history.step().is_ok() {}
+        assert!(history.machine().is_halted());
+        while history.step_back() {}
+        assert_eq!(history.machine(), &before);
+    }
+
+    #[test]
+    fn only_the_limit_is_kept() {
+        let mut history = History::with_limit(fibonacci(), 3);
+        let states = record(&mut history, 10);
+        assert_eq!(history.len(), 3);
+        assert!(history.step_back());
+        assert!(history.step_back());
+        assert!(history.step_back());
+        assert_eq!(history.machine(), &states[7]);
+        assert!(!history.step_back());
+        assert_eq!(history.machine(), &states[7]);
+    }
+}
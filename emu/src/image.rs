@@ -0,0 +1,509 @@
+//! Reading memory images in the formats assemblers write them in
+//!
+//! Besides raw binaries, these are the text formats customasm can emit: hex and binary strings,
+//! Intel HEX, and Logisim memory files. Hex is lenient enough to take bytes pasted from anywhere,
+//! like `2a 40`, `0x2a, 0x40` or `2a40`.
+
+use crate::{located::Located, Version};
+use clap::ArgEnum;
+use fs_err as fs;
+use std::{error, fmt, io, path::Path, str};
+
+/// The format of a memory image
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ArgEnum)]
+pub enum Format {
+    /// The bytes of the image as they are
+    Raw,
+    /// Hex digits, optionally split up by whitespace or commas and prefixed by 0x
+    Hex,
+    /// Intel HEX records
+    Ihex,
+    /// Binary digits, eight to a byte
+    Binstr,
+    /// A Logisim memory file, starting `v2.0 raw`
+    Logisim,
+}
+
+impl Format {
+    /// Guess the format of an image from its extension, and its contents where that's ambiguous
+    ///
+    /// Anything unrecognised is read as raw.
+    #[must_use]
+    pub fn detect(path: &Path, contents: &[u8]) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("ihx" | "ihex") => Self::Ihex,
+            Some("binstr") => Self::Binstr,
+            Some("logisim") => Self::Logisim,
+            Some("hex" | "hexstr" | "txt") => {
+                let text = String::from_utf8_lossy(contents);
+                let text = text.trim_start();
+                if text.starts_with(':') {
+                    Self::Ihex
+                } else if text.starts_with("v2.0 raw") {
+                    Self::Logisim
+                } else {
+                    Self::Hex
+                }
+            }
+            _ => Self::Raw,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    NotText,
+    NotHex(String),
+    NotBinary(String),
+    /// The digits end part way through a byte, with this many bits left over
+    PartialByte {
+        bits: usize,
+    },
+    /// A Logisim value doesn't fit in a byte
+    TooLarge(String),
+    /// An address or repeat count puts data past the end of the largest memory
+    PastEndOfMemory {
+        len: usize,
+        max: usize,
+    },
+    MissingLogisimHeader,
+    /// An Intel HEX record is malformed
+    BadRecord(String),
+    Checksum {
+        expected: u8,
+        found: u8,
+    },
+    UnsupportedRecord(u8),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "could not read image"),
+            Self::NotText => write!(f, "image isn't text"),
+            Self::NotHex(s) => write!(f, "`{}` isn't hex", s),
+            Self::NotBinary(s) => write!(f, "`{}` isn't binary", s),
+            Self::PartialByte { bits } => write!(f, "{} bits left over after the last byte", bits),
+            Self::TooLarge(s) => write!(f, "`{}` doesn't fit in a byte", s),
+            Self::PastEndOfMemory { len, max } => write!(
+                f,
+                "image would be {} bytes, but memory is at most {} bytes",
+                len, max
+            ),
+            Self::MissingLogisimHeader => write!(f, "Logisim image doesn't start `v2.0 raw`"),
+            Self::BadRecord(s) => write!(f, "malformed record: {}", s),
+            Self::Checksum { expected, found } => write!(
+                f,
+                "record checksum is 0x{:02x}, but should be 0x{:02x}",
+                found, expected
+            ),
+            Self::UnsupportedRecord(t) => write!(f, "unsupported record type 0x{:02x}", t),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub type Error = Located<ErrorKind>;
+
+/// The most bytes an image can place data in, as no version has more memory
+///
+/// Intel HEX addresses and Logisim repeat counts are checked against this, as they could
+/// otherwise ask for gigabytes from a few bytes of text.
+const MAX_LEN: usize = Version::V3.memory_size();
+
+/// Read an image from a file, detecting its format from the file if it isn't given
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or isn't valid in its format.
+pub fn load(path: impl AsRef<Path>, format: Option<Format>) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+    let contents = fs::read(path).map_err(|e| Error::new(0, ErrorKind::Io(e)))?;
+    let format = format.unwrap_or_else(|| Format::detect(path, &contents));
+    parse(format, &contents).map_err(|e| e.in_file(path))
+}
+
+/// Read an image in the given format
+///
+/// # Errors
+///
+/// Returns an error if the image isn't valid in the format.
+pub fn parse(format: Format, contents: &[u8]) -> Result<Vec<u8>, Error> {
+    if format == Format::Raw {
+        return Ok(contents.to_vec());
+    }
+    let text = str::from_utf8(contents).map_err(|_| Error::new(0, ErrorKind::NotText))?;
+    match format {
+        Format::Raw => unreachable!("raw images aren't text"),
+        Format::Hex => hex(text),
+        Format::Ihex => ihex(text),
+        Format::Binstr => binstr(text),
+        Format::Logisim => logisim(text),
+    }
+}
+
+fn hex(text: &str) -> Result<Vec<u8>, Error> {
+    let mut image = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let tokens = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|t| !t.is_empty());
+        for token in tokens {
+            let not_hex = || Error::new(i + 1, ErrorKind::NotHex(token.to_string()));
+            match token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+            {
+                // A prefixed token is always a single byte, which may be missing its leading 0
+                Some(digits) => {
+                    image.push(u8::from_str_radix(digits, 16).map_err(|_| not_hex())?);
+                }
+                None if token.len() == 1 => {
+                    image.push(u8::from_str_radix(token, 16).map_err(|_| not_hex())?);
+                }
+                None => image.extend(hex_bytes(token).ok_or_else(not_hex)?),
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Decode a run of hex digit pairs
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn binstr(text: &str) -> Result<Vec<u8>, Error> {
+    let mut bits = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        for c in line.chars().filter(|c| !c.is_whitespace()) {
+            match c {
+                '0' => bits.push(0),
+                '1' => bits.push(1),
+                _ => return Err(Error::new(i + 1, ErrorKind::NotBinary(c.to_string()))),
+            }
+        }
+    }
+    if !bits.len().is_multiple_of(8) {
+        return Err(Error::new(
+            0,
+            ErrorKind::PartialByte {
+                bits: bits.len() % 8,
+            },
+        ));
+    }
+    Ok(bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | bit))
+        .collect())
+}
+
+fn logisim(text: &str) -> Result<Vec<u8>, Error> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty());
+    match lines.next() {
+        Some((_, "v2.0 raw")) => {}
+        Some((line, _)) => return Err(Error::new(line, ErrorKind::MissingLogisimHeader)),
+        None => return Err(Error::new(0, ErrorKind::MissingLogisimHeader)),
+    }
+
+    let mut image = Vec::new();
+    for (line, text) in lines {
+        for token in text.split_whitespace() {
+            // `n*value` is the value repeated n times
+            let (count, value) = match token.split_once('*') {
+                Some((count, value)) => (
+                    count
+                        .parse()
+                        .map_err(|_| Error::new(line, ErrorKind::NotHex(token.to_string())))?,
+                    value,
+                ),
+                None => (1, token),
+            };
+            let value = u32::from_str_radix(value, 16)
+                .map_err(|_| Error::new(line, ErrorKind::NotHex(token.to_string())))?;
+            let value = u8::try_from(value)
+                .map_err(|_| Error::new(line, ErrorKind::TooLarge(token.to_string())))?;
+            let len = image.len().saturating_add(count);
+            if len > MAX_LEN {
+                return Err(Error::new(
+                    line,
+                    ErrorKind::PastEndOfMemory { len, max: MAX_LEN },
+                ));
+            }
+            image.extend(std::iter::repeat_n(value, count));
+        }
+    }
+    Ok(image)
+}
+
+fn ihex(text: &str) -> Result<Vec<u8>, Error> {
+    let mut image = Vec::new();
+    // The address that record addresses are relative to, from extended address records
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let bad = |s: &str| Error::new(line_number, ErrorKind::BadRecord(s.to_string()));
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| bad("doesn't start with `:`"))?;
+        let bytes = hex_bytes(record).ok_or_else(|| bad("isn't hex"))?;
+        if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+            return Err(bad("length doesn't match its byte count"));
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = body
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+        if checksum[0] != expected {
+            return Err(Error::new(
+                line_number,
+                ErrorKind::Checksum {
+                    expected,
+                    found: checksum[0],
+                },
+            ));
+        }
+
+        let address = usize::from(u16::from_be_bytes([body[1], body[2]]));
+        let data = &body[4..];
+        let extended = || usize::from(u16::from_be_bytes([data[0], data[1]]));
+        match body[3] {
+            0x00 => {
+                let start = base + address;
+                let end = start + data.len();
+                if end > MAX_LEN {
+                    let kind = ErrorKind::PastEndOfMemory {
+                        len: end,
+                        max: MAX_LEN,
+                    };
+                    return Err(Error::new(line_number, kind));
+                }
+                if image.len() < end {
+                    image.resize(end, 0);
+                }
+                image[start..end].copy_from_slice(data);
+            }
+            0x01 => break,
+            0x02 if data.len() == 2 => base = extended() << 4,
+            0x04 if data.len() == 2 => base = extended() << 16,
+            // Start addresses don't matter, as the machines always start at 0
+            0x03 | 0x05 => {}
+            0x02 | 0x04 => return Err(bad("extended address isn't 2 bytes")),
+            t => return Err(Error::new(line_number, ErrorKind::UnsupportedRecord(t))),
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(format: Format, text: &str) -> Result<Vec<u8>, Error> {
+        parse(format, text.as_bytes())
+    }
+
+    #[test]
+    fn detect() {
+        let detect =
+            |path: &str, contents: &str| Format::detect(Path::new(path), contents.as_bytes());
+        assert_eq!(detect("a.bin", ":00000001FF"), Format::Raw);
+        assert_eq!(detect("a", "2a"), Format::Raw);
+        assert_eq!(detect("a.IHX", ""), Format::Ihex);
+        assert_eq!(detect("a.ihex", ""), Format::Ihex);
+        assert_eq!(detect("a.binstr", ""), Format::Binstr);
+        assert_eq!(detect("a.logisim", ""), Format::Logisim);
+        assert_eq!(detect("a.hex", "2a 40"), Format::Hex);
+        assert_eq!(detect("a.hexstr", "2a40"), Format::Hex);
+        assert_eq!(detect("a.hex", "\n:00000001FF"), Format::Ihex);
+        assert_eq!(detect("a.txt", "v2.0 raw\n2a"), Format::Logisim);
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!(parse(Format::Raw, &[0xff, 0x00]).unwrap(), [0xff, 0x00]);
+        assert!(matches!(
+            parse(Format::Hex, &[0xff]).unwrap_err().kind,
+            ErrorKind::NotText
+        ));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(
+            parse_str(Format::Hex, "2a 40\ne0").unwrap(),
+            [0x2a, 0x40, 0xe0]
+        );
+        assert_eq!(
+            parse_str(Format::Hex, "0x2a, 0X4,f").unwrap(),
+            [0x2a, 0x04, 0x0f]
+        );
+        assert_eq!(
+            parse_str(Format::Hex, "2a40E0").unwrap(),
+            [0x2a, 0x40, 0xe0]
+        );
+        assert!(parse_str(Format::Hex, "").unwrap().is_empty());
+        for text in ["2a4", "0x2g", "0x100", "zz"] {
+            assert!(
+                matches!(
+                    parse_str(Format::Hex, text).unwrap_err().kind,
+                    ErrorKind::NotHex(_)
+                ),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(parse_str(Format::Hex, "2a\n\nxy").unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn binstr() {
+        assert_eq!(
+            parse_str(Format::Binstr, "00101010\n0100 0000").unwrap(),
+            [0x2a, 0x40]
+        );
+        assert!(matches!(
+            parse_str(Format::Binstr, "0010101").unwrap_err().kind,
+            ErrorKind::PartialByte { bits: 7 }
+        ));
+        assert!(matches!(
+            parse_str(Format::Binstr, "0000\n0002").unwrap_err(),
+            Error {
+                line: 2,
+                kind: ErrorKind::NotBinary(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn logisim() {
+        assert_eq!(
+            parse_str(Format::Logisim, "v2.0 raw\n# comment\n2a 40 3*e0\nff").unwrap(),
+            [0x2a, 0x40, 0xe0, 0xe0, 0xe0, 0xff]
+        );
+        assert_eq!(
+            parse_str(Format::Logisim, "v2.0 raw\n256*0").unwrap().len(),
+            256
+        );
+        assert!(matches!(
+            parse_str(Format::Logisim, "2a").unwrap_err().kind,
+            ErrorKind::MissingLogisimHeader
+        ));
+        assert!(matches!(
+            parse_str(Format::Logisim, "").unwrap_err().kind,
+            ErrorKind::MissingLogisimHeader
+        ));
+        assert!(matches!(
+            parse_str(Format::Logisim, "v2.0 raw\n100")
+                .unwrap_err()
+                .kind,
+            ErrorKind::TooLarge(_)
+        ));
+        assert!(matches!(
+            parse_str(Format::Logisim, "v2.0 raw\nx*0")
+                .unwrap_err()
+                .kind,
+            ErrorKind::NotHex(_)
+        ));
+        assert!(matches!(
+            parse_str(Format::Logisim, "v2.0 raw\n1 256*0").unwrap_err(),
+            Error {
+                line: 2,
+                kind: ErrorKind::PastEndOfMemory { len: 257, max: 256 },
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_str(Format::Logisim, "v2.0 raw\n99999999999999*0")
+                .unwrap_err()
+                .kind,
+            ErrorKind::PastEndOfMemory { .. }
+        ));
+    }
+
+    #[test]
+    fn ihex() {
+        assert_eq!(
+            parse_str(
+                Format::Ihex,
+                ":030000002A40E0B3\n:0400000500000000F7\n:01001000FFF0\n:00000001FF\n:0100000001FE"
+            )
+            .unwrap(),
+            [&[0x2a, 0x40, 0xe0][..], &[0; 13], &[0xff]].concat()
+        );
+        assert_eq!(
+            parse_str(Format::Ihex, ":0200FE000102FD").unwrap()[254..],
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn ihex_errors() {
+        let kind = |text: &str| parse_str(Format::Ihex, text).unwrap_err().kind;
+        assert!(matches!(kind("030000002A40E0B3"), ErrorKind::BadRecord(_)));
+        assert!(matches!(kind(":0300002A40E0B3"), ErrorKind::BadRecord(_)));
+        assert!(matches!(kind(":030000002A40E0"), ErrorKind::BadRecord(_)));
+        assert!(matches!(
+            kind(":030000002A40E0B4"),
+            ErrorKind::Checksum {
+                expected: 0xb3,
+                found: 0xb4
+            }
+        ));
+        assert!(matches!(
+            kind(":00000006FA"),
+            ErrorKind::UnsupportedRecord(6)
+        ));
+        assert!(matches!(
+            kind(":0200FF000102FC"),
+            ErrorKind::PastEndOfMemory { len: 257, max: 256 }
+        ));
+        // Extended addresses move everything after them past the end of memory
+        for base in [":020000021000EC", ":020000040001F9"] {
+            assert!(matches!(
+                kind(&format!("{}\n:0100000001FE", base)),
+                ErrorKind::PastEndOfMemory { .. }
+            ));
+        }
+        // Nothing after the end of file record is read
+        assert!(parse_str(Format::Ihex, ":00000001FF\n\n:0200FF000102FC")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            parse_str(Format::Ihex, "\n:030000002A40E0B4")
+                .unwrap_err()
+                .line,
+            2
+        );
+    }
+}
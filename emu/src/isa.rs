@@ -0,0 +1,177 @@
+//! The instruction set of each version, as written in `asm/v*/ruledef.S`
+//!
+//! This only uses `std`, so the build script can assemble the examples with the same tables.
+
+/// A version of the PuttPc
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    V1,
+    V2,
+    #[default]
+    V3,
+}
+
+impl Version {
+    /// Every version, oldest first
+    pub const ALL: [Self; 3] = [Self::V1, Self::V2, Self::V3];
+
+    /// The number of bytes of memory in this version
+    #[must_use]
+    pub const fn memory_size(self) -> usize {
+        match self {
+            Self::V1 | Self::V2 => 16,
+            Self::V3 => 256,
+        }
+    }
+
+    /// The version named `name`, like `v2`, in any case
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|v| format!("{:?}", v).eq_ignore_ascii_case(name))
+    }
+}
+
+/// The kind of an instruction operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// The `A` register, written `%a`
+    A,
+    /// The `B` register, written `%b`
+    B,
+    /// An immediate value
+    Value,
+    /// A memory address, written with a leading `$` in v3
+    Address,
+}
+
+/// An instruction, as `(opcode, mnemonic, operands)`
+pub type Rule = (u8, &'static str, &'static [Operand]);
+
+use Operand as O;
+
+const V1: &[Rule] = &[
+    (0x0, "nop", &[]),
+    (0x1, "ldav", &[O::Value]),
+    (0x2, "ldam", &[O::Address]),
+    (0x3, "sta", &[O::Address]),
+    (0x4, "txb", &[]),
+    (0x5, "addm", &[O::Address]),
+    (0x6, "subm", &[O::Address]),
+    (0x7, "jmp", &[O::Address]),
+    (0x8, "jz", &[O::Address]),
+    (0x9, "jc", &[O::Address]),
+    (0xE, "out", &[]),
+    (0xF, "hlt", &[]),
+];
+
+const V2: &[Rule] = &[
+    (0x0, "nop", &[]),
+    (0x1, "ldav", &[O::Value]),
+    (0x2, "ldam", &[O::Address]),
+    (0x3, "sta", &[O::Address]),
+    (0x4, "txb", &[]),
+    (0x5, "add", &[]),
+    (0x6, "addv", &[O::Value]),
+    (0x7, "addm", &[O::Address]),
+    (0x8, "sub", &[]),
+    (0x9, "subv", &[O::Value]),
+    (0xA, "subm", &[O::Address]),
+    (0xB, "jmp", &[O::Address]),
+    (0xC, "jz", &[O::Address]),
+    (0xD, "jc", &[O::Address]),
+    (0xE, "out", &[]),
+    (0xF, "hlt", &[]),
+];
+
+const V3: &[Rule] = &[
+    (0x00, "nop", &[]),
+    (0x01, "mov", &[O::A, O::B]),
+    (0x02, "mov", &[O::A, O::Value]),
+    (0x03, "mov", &[O::A, O::Address]),
+    (0x04, "mov", &[O::B, O::A]),
+    (0x05, "mov", &[O::B, O::Value]),
+    (0x06, "mov", &[O::B, O::Address]),
+    (0x07, "mov", &[O::Address, O::A]),
+    (0x08, "mov", &[O::Address, O::B]),
+    (0x09, "mov", &[O::Address, O::Value]),
+    (0x0a, "mov", &[O::Address, O::Address]),
+    (0x10, "add", &[O::A, O::B]),
+    (0x11, "add", &[O::A, O::Value]),
+    (0x12, "add", &[O::A, O::Address]),
+    (0x13, "add", &[O::Value, O::B]),
+    (0x14, "add", &[O::Value, O::Value]),
+    (0x15, "add", &[O::Value, O::Address]),
+    (0x16, "add", &[O::Address, O::B]),
+    (0x17, "add", &[O::Address, O::Value]),
+    (0x18, "add", &[O::Address, O::Address]),
+    (0x20, "sub", &[O::A, O::B]),
+    (0x21, "sub", &[O::A, O::Value]),
+    (0x22, "sub", &[O::A, O::Address]),
+    (0x23, "sub", &[O::Value, O::B]),
+    (0x24, "sub", &[O::Value, O::Value]),
+    (0x25, "sub", &[O::Value, O::Address]),
+    (0x26, "sub", &[O::Address, O::B]),
+    (0x27, "sub", &[O::Address, O::Value]),
+    (0x28, "sub", &[O::Address, O::Address]),
+    (0xD0, "jmp", &[O::Address]),
+    (0xD1, "jz", &[O::Address]),
+    (0xD2, "jnz", &[O::Address]),
+    (0xD3, "jc", &[O::Address]),
+    (0xD4, "jnc", &[O::Address]),
+    (0xE0, "out", &[]),
+    (0xFF, "hlt", &[]),
+];
+
+/// Every instruction of a version, in opcode order
+#[must_use]
+pub fn rules(version: Version) -> &'static [Rule] {
+    match version {
+        Version::V1 => V1,
+        Version::V2 => V2,
+        Version::V3 => V3,
+    }
+}
+
+/// The mnemonic and operands of an opcode, or `None` if it isn't an instruction
+#[must_use]
+pub fn syntax(version: Version, opcode: u8) -> Option<(&'static str, &'static [Operand])> {
+    rules(version)
+        .iter()
+        .find(|(o, _, _)| *o == opcode)
+        .map(|&(_, mnemonic, operands)| (mnemonic, operands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{v1, v2, v3};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn rules_match_instructions() {
+        let opcodes = |version| rules(version).iter().map(|r| r.0).collect::<Vec<_>>();
+        let v1: Vec<u8> = (0..=255)
+            .filter(|&op| v1::Instruction::try_from(op).is_ok())
+            .collect();
+        let v2: Vec<u8> = (0..=255)
+            .filter(|&op| v2::Instruction::try_from(op).is_ok())
+            .collect();
+        let v3: Vec<u8> = (0..=255)
+            .filter(|&op| v3::Instruction::try_from(op).is_ok())
+            .collect();
+        assert_eq!(opcodes(Version::V1), v1);
+        assert_eq!(opcodes(Version::V2), v2);
+        assert_eq!(opcodes(Version::V3), v3);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Version::from_name("v1"), Some(Version::V1));
+        assert_eq!(Version::from_name("V3"), Some(Version::V3));
+        assert_eq!(Version::from_name("v4"), None);
+        assert_eq!(syntax(Version::V2, 0x7), Some(("addm", &[O::Address][..])));
+        assert_eq!(syntax(Version::V1, 0xA), None);
+    }
+}
@@ -1,10 +1,157 @@
-use std::fmt::{Binary, Debug, Display, LowerHex, Octal, UpperHex};
+use clap::{ArgEnum, PossibleValue};
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt::{self, Binary, Debug, Display, LowerHex, Octal, UpperHex},
+    hash::Hash,
+};
 
+pub mod asm;
+pub mod disasm;
+pub mod golden;
+pub mod history;
+pub mod image;
+pub mod isa;
+pub mod limits;
+pub mod lint;
+pub mod located;
+pub mod microcode;
+pub mod nbt;
+pub mod region;
+pub mod rom;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
 pub mod v1;
 pub mod v2;
 pub mod v3;
+pub mod vcd;
+pub mod world;
+pub use history::History;
+pub use limits::{Bounded, Budget, Limits, LoopDetector, Outcome, Run};
+pub use microcode::Microcode;
+pub use rom::Rom;
+pub use snapshot::Snapshot;
+pub use symbols::Symbols;
 pub use v2::*;
 
+pub use isa::Version;
+
+// `isa` has no dependencies, so the command line's names for versions are given here
+impl ArgEnum for Version {
+    fn value_variants<'a>() -> &'a [Self] {
+        &Self::ALL
+    }
+
+    fn to_possible_value<'a>(&self) -> Option<PossibleValue<'a>> {
+        Some(PossibleValue::new(match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+            Self::V3 => "v3",
+        }))
+    }
+}
+
+/// The name of a register from its index in `Machine::regs`
+#[must_use]
+pub fn register_name(r: usize) -> String {
+    u8::try_from(r)
+        .ok()
+        .and_then(|r| Register::try_from(r).ok())
+        .map_or_else(|| format!("r{}", r), |r| format!("{:?}", r))
+}
+
+/// The name and bit of every flag in `Machine::flags`
+#[must_use]
+pub fn flag_names() -> Vec<(String, u32)> {
+    (0..32)
+        .map(|i| 1 << i)
+        // The `Debug` of a single flag is its name
+        .filter_map(|bit| Flags::from_bits(bit).map(|f| (format!("{:?}", f), bit)))
+        .collect()
+}
+
+/// How much of a machine `Machine::reset` clears
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Reset {
+    /// Assert `RESET`, clearing the registers, flags and micro counter but keeping memory
+    #[default]
+    Soft,
+    /// Assert `RESET` and `RESET_RAM`, also clearing memory like a newly built machine
+    PowerOn,
+}
+
+/// A fault that stops a machine
+///
+/// A machine that faults also halts, so it won't be stepped further by `run` or its iterator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MachineError {
+    /// The instruction register holds a byte that isn't an instruction
+    InvalidOpcode { address: u8, opcode: u8 },
+    /// The input doesn't fit in memory
+    ProgramTooLarge { len: usize, max: usize },
+    /// The microcode given to `Machine::set_microcode` is for another version
+    WrongMicrocode {
+        machine: Version,
+        microcode: Version,
+    },
+    /// The program counter ran off the end of memory
+    CounterOverflow { counter: u8 },
+    /// Memory was accessed at an address it doesn't have
+    AddressOutOfRange { address: u8 },
+    /// More than one output drove the data bus at once, with `Machine::set_check_bus` enabled
+    BusContention {
+        version: Version,
+        opcode: u8,
+        micro: usize,
+        /// The bits of the `Controls` driving the bus
+        sources: u32,
+    },
+}
+
+impl Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode { address, opcode } => {
+                write!(f, "invalid opcode 0x{:02x} at address {}", opcode, address)
+            }
+            Self::ProgramTooLarge { len, max } => {
+                write!(f, "program is {} bytes, but memory is {} bytes", len, max)
+            }
+            Self::WrongMicrocode { machine, microcode } => write!(
+                f,
+                "microcode is for {:?}, but the machine is {:?}",
+                microcode, machine
+            ),
+            Self::CounterOverflow { counter } => {
+                write!(f, "program counter {} is past the end of memory", counter)
+            }
+            Self::AddressOutOfRange { address } => {
+                write!(f, "memory address {} is out of range", address)
+            }
+            Self::BusContention {
+                version,
+                opcode,
+                micro,
+                sources,
+            } => {
+                let mnemonic = isa::syntax(*version, *opcode)
+                    .map_or("an invalid instruction", |(mnemonic, _)| mnemonic);
+                write!(
+                    f,
+                    "bus contention in microstep {} of {} (opcode 0x{:02x}) between {}",
+                    micro,
+                    mnemonic,
+                    opcode,
+                    microcode::format_controls(*version, *sources)
+                )
+            }
+        }
+    }
+}
+
+impl Error for MachineError {}
+
 pub trait Machine: IntoIterator + Display {
     /// The type of a single unit of input
     type Input;
@@ -12,30 +159,184 @@ pub trait Machine: IntoIterator + Display {
     /// The type of a single unit of output
     type Output: Binary + Debug + Display + LowerHex + Octal + UpperHex;
 
+    /// The state besides registers and memory: controls, flags and micro
+    type Latches: Clone + Debug;
+
     /// Whether the machine has halted
     fn is_halted(&self) -> bool;
 
+    /// The current microstep within the instruction being executed
+    fn micro(&self) -> usize;
+
+    /// The version of PuttPc this machine emulates
+    fn version(&self) -> Version;
+
+    /// The registers, indexed by `Register`
+    fn regs(&self) -> &[u8];
+
+    /// The registers, mutably, indexed by `Register`
+    fn regs_mut(&mut self) -> &mut [u8];
+
+    /// The whole of memory
+    fn memory(&self) -> &[u8];
+
+    /// The whole of memory, mutably
+    fn memory_mut(&mut self) -> &mut [u8];
+
+    /// The bits of the `Controls` for the next step
+    fn controls(&self) -> u32;
+
+    /// The bits of the `Flags` register
+    fn flags(&self) -> u32;
+
+    /// The value on the data bus during the next step, or `None` if the step would fault
+    fn bus(&self) -> Option<u8>;
+
+    /// A copy of the state besides registers and memory
+    fn latches(&self) -> Self::Latches;
+
+    /// Restore state previously returned by `latches`
+    fn set_latches(&mut self, latches: Self::Latches);
+
+    /// The microcode the machine is running
+    fn microcode(&self) -> &Microcode;
+
+    /// Reset the machine to the start of the first instruction, and out of any halt
+    ///
+    /// A soft reset keeps memory, so the program runs again, though anything it wrote to
+    /// memory stays written.
+    fn reset(&mut self, kind: Reset);
+
+    /// Whether to fault when more than one output drives the data bus in the same microstep
+    ///
+    /// Hardware can't OR outputs together like the emulator does, so this finds microcode that
+    /// would corrupt the bus in game.
+    fn set_check_bus(&mut self, check: bool);
+
+    /// Replace the microcode the machine is running
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the microcode unchanged, if it is for a different version.
+    fn set_microcode(&mut self, microcode: Microcode) -> Result<(), MachineError>;
+
     /// Set the full input of the machine
-    fn set_input(&mut self, input: &[Self::Input]);
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in the machine.
+    fn set_input(&mut self, input: &[Self::Input]) -> Result<(), MachineError>;
 
     /// Perform one step of the machine
-    fn step(&mut self) -> Option<Self::Output>;
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and halts the machine, if the step faults.
+    fn step(&mut self) -> Result<Option<Self::Output>, MachineError>;
+
+    /// Perform steps until the machine finishes the current instruction
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and halts the machine, if a step faults.
+    fn step_instruction(&mut self) -> Result<Vec<Self::Output>, MachineError> {
+        let mut output = Vec::new();
+        loop {
+            output.extend(self.step()?);
+            if self.micro() == 0 {
+                break Ok(output);
+            }
+        }
+    }
 
     /// Run the machine until some halting condition is met
-    fn run(&mut self) -> Vec<Self::Output>;
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any step faults.
+    fn run(&mut self) -> Result<Vec<Self::Output>, MachineError>;
+
+    /// Run the machine until it halts or one of the limits is reached
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any step faults.
+    fn run_bounded(&mut self, limits: Limits) -> Result<Run<Self::Output>, MachineError>
+    where
+        Self: Sized + Clone + Eq + Hash,
+    {
+        self.run_bounded_with(limits, Self::step)
+    }
+
+    /// Like `run_bounded`, but calling `step` to take each step
+    ///
+    /// `step` should step the machine once and return what it output, and can look at the
+    /// machine before and after to trace or display it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error `step` returns.
+    fn run_bounded_with<E>(
+        &mut self,
+        limits: Limits,
+        mut step: impl FnMut(&mut Self) -> Result<Option<Self::Output>, E>,
+    ) -> Result<Run<Self::Output>, E>
+    where
+        Self: Sized + Clone + Eq + Hash,
+    {
+        let mut budget = Budget::new(limits);
+        let mut output = Vec::new();
+        let outcome = loop {
+            if let Some(outcome) = budget.check(self) {
+                break outcome;
+            }
+            output.extend(step(self)?);
+            budget.count(self);
+        };
+
+        Ok(Run {
+            output,
+            outcome,
+            steps: budget.steps,
+            instructions: budget.instructions,
+        })
+    }
 
     /// Run the machine until some halting condition is met, with the input provided
-    fn run_with_input(&mut self, input: &[Self::Input]) -> Vec<Self::Output> {
-        self.set_input(input);
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit or any step faults.
+    fn run_with_input(&mut self, input: &[Self::Input]) -> Result<Vec<Self::Output>, MachineError> {
+        self.set_input(input)?;
         self.run()
     }
 
     /// Return an iterator of the output, with the input provided
-    fn into_iter_with_input(mut self, input: &[Self::Input]) -> <Self as IntoIterator>::IntoIter
+    ///
+    /// The iterator only ends when the machine halts, so use `into_iter_bounded` for programs
+    /// that might not.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in the machine.
+    fn into_iter_with_input(
+        mut self,
+        input: &[Self::Input],
+    ) -> Result<<Self as IntoIterator>::IntoIter, MachineError>
     where
         Self: Sized,
     {
-        self.set_input(input);
-        self.into_iter()
+        self.set_input(input)?;
+        Ok(self.into_iter())
+    }
+
+    /// Return an iterator of the output that stops when one of the limits is reached
+    #[must_use]
+    fn into_iter_bounded(self, limits: Limits) -> Bounded<Self>
+    where
+        Self: Sized + Clone + Eq + Hash,
+    {
+        Bounded::new(self, limits)
     }
 }
@@ -0,0 +1,248 @@
+//! Bounded runs of a `Machine`, for programs that might never halt
+
+use crate::{Machine, MachineError};
+use std::{collections::HashSet, hash::Hash};
+
+/// Limits on how long `Machine::run_bounded` may run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most steps (microcycles) to run
+    pub max_steps: Option<u64>,
+    /// The most instructions to run
+    pub max_instructions: Option<u64>,
+    /// Whether to stop once the machine is back in a state it has already been in
+    pub detect_loops: bool,
+}
+
+impl Limits {
+    /// Whether a run of this many steps and instructions has used up its budget
+    #[must_use]
+    pub fn exceeded(&self, steps: u64, instructions: u64) -> bool {
+        self.max_steps.is_some_and(|max| steps >= max)
+            || self.max_instructions.is_some_and(|max| instructions >= max)
+    }
+}
+
+/// Why a bounded run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The machine halted
+    Halted,
+    /// The step or instruction budget ran out first
+    BudgetExhausted,
+    /// The machine repeated an earlier state, so it will never halt
+    Looping,
+}
+
+/// The result of a bounded run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run<O> {
+    pub output: Vec<O>,
+    pub outcome: Outcome,
+    pub steps: u64,
+    pub instructions: u64,
+}
+
+/// Remembers every state a machine has been in at the start of an instruction
+///
+/// The machines are deterministic, so seeing a state twice means they are stuck in a loop. Whole
+/// states are kept rather than just their hashes, so a hash collision can't cause a false positive.
+#[derive(Debug, Clone)]
+pub struct LoopDetector<M> {
+    seen: HashSet<M>,
+}
+
+impl<M: Machine + Clone + Eq + Hash> LoopDetector<M> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Record the machine's state, returning whether it has been in this state before
+    ///
+    /// Only states at instruction boundaries are recorded, which is enough to catch every loop.
+    pub fn check(&mut self, machine: &M) -> bool {
+        machine.micro() == 0 && !self.seen.insert(machine.clone())
+    }
+}
+
+impl<M: Machine + Clone + Eq + Hash> Default for LoopDetector<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How far a bounded run has got, shared by every way of running within `Limits`
+///
+/// For stepping a machine some other way than `Machine::run_bounded_with`, like through a
+/// `History`: call `check` before each step and `count` after it.
+#[derive(Debug, Clone)]
+pub struct Budget<M> {
+    limits: Limits,
+    loops: LoopDetector<M>,
+    pub steps: u64,
+    pub instructions: u64,
+}
+
+impl<M: Machine + Clone + Eq + Hash> Budget<M> {
+    #[must_use]
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            loops: LoopDetector::new(),
+            steps: 0,
+            instructions: 0,
+        }
+    }
+
+    /// Why the run should stop before the machine's next step, if it should
+    pub fn check(&mut self, machine: &M) -> Option<Outcome> {
+        if machine.is_halted() {
+            Some(Outcome::Halted)
+        } else if self.limits.exceeded(self.steps, self.instructions) {
+            Some(Outcome::BudgetExhausted)
+        } else if self.limits.detect_loops && self.loops.check(machine) {
+            Some(Outcome::Looping)
+        } else {
+            None
+        }
+    }
+
+    /// Count a step the machine has just taken
+    pub fn count(&mut self, machine: &M) {
+        self.steps += 1;
+        if machine.micro() == 0 {
+            self.instructions += 1;
+        }
+    }
+}
+
+/// An iterator of a machine's output that stops when one of the limits is reached
+///
+/// Returned by `Machine::into_iter_bounded`.
+#[derive(Debug, Clone)]
+pub struct Bounded<M> {
+    machine: M,
+    budget: Budget<M>,
+    outcome: Option<Outcome>,
+}
+
+impl<M: Machine + Clone + Eq + Hash> Bounded<M> {
+    pub(crate) fn new(machine: M, limits: Limits) -> Self {
+        Self {
+            machine,
+            budget: Budget::new(limits),
+            outcome: None,
+        }
+    }
+
+    /// Why the iterator stopped, or `None` if it hasn't yet
+    #[must_use]
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
+
+    /// The number of steps taken so far
+    #[must_use]
+    pub fn steps(&self) -> u64 {
+        self.budget.steps
+    }
+
+    /// The number of instructions finished so far
+    #[must_use]
+    pub fn instructions(&self) -> u64 {
+        self.budget.instructions
+    }
+
+    /// The machine, in the state it was left in
+    #[must_use]
+    pub fn into_inner(self) -> M {
+        self.machine
+    }
+}
+
+impl<M: Machine + Clone + Eq + Hash> Iterator for Bounded<M> {
+    type Item = Result<M::Output, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.outcome.is_some() {
+                break None;
+            }
+            if let Some(outcome) = self.budget.check(&self.machine) {
+                self.outcome = Some(outcome);
+                break None;
+            }
+            let out = self.machine.step();
+            self.budget.count(&self.machine);
+            match out {
+                Ok(Some(out)) => break Some(Ok(out)),
+                Ok(None) => {}
+                Err(e) => break Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v3::PuttPc;
+
+    /// `loop: out` then `jmp ${loop}`, outputting 0 forever
+    const FOREVER: &[u8] = &[0xe0, 0xd0, 0x00];
+
+    #[test]
+    fn bounded_iterator_stops_at_the_budget() {
+        let limits = Limits {
+            max_instructions: Some(4),
+            ..Limits::default()
+        };
+        let mut iter = PuttPc::with_input(FOREVER)
+            .unwrap()
+            .into_iter_bounded(limits);
+        assert_eq!(
+            iter.by_ref().collect::<Result<Vec<_>, _>>().unwrap(),
+            [0, 0]
+        );
+        assert_eq!(iter.outcome(), Some(Outcome::BudgetExhausted));
+        assert_eq!(iter.instructions(), 4);
+    }
+
+    #[test]
+    fn bounded_iterator_stops_at_a_loop() {
+        let limits = Limits {
+            detect_loops: true,
+            ..Limits::default()
+        };
+        let mut iter = PuttPc::with_input(FOREVER)
+            .unwrap()
+            .into_iter_bounded(limits);
+        let output = iter.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!output.is_empty() && output.iter().all(|&out| out == 0));
+        assert_eq!(iter.outcome(), Some(Outcome::Looping));
+    }
+
+    #[test]
+    fn run_bounded_matches_the_iterator() {
+        let limits = Limits {
+            max_steps: Some(100),
+            ..Limits::default()
+        };
+        let mut machine = PuttPc::with_input(FOREVER).unwrap();
+        let mut iter = machine.clone().into_iter_bounded(limits);
+        let run = machine.run_bounded(limits).unwrap();
+        assert_eq!(
+            run.output,
+            iter.by_ref().collect::<Result<Vec<_>, _>>().unwrap()
+        );
+        assert_eq!(run.outcome, Outcome::BudgetExhausted);
+        assert_eq!(
+            (run.steps, run.instructions),
+            (iter.steps(), iter.instructions())
+        );
+        assert!(iter.into_inner() == machine);
+    }
+}
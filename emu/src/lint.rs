@@ -0,0 +1,190 @@
+//! Static checks of microcode, for bugs that the emulator would otherwise run straight past
+//!
+//! Steps past the end of the micro counter can't be represented in a `Microcode`, so
+//! `Microcode::parse` reports those instead.
+
+use crate::{isa, microcode, Microcode, Version};
+use std::fmt;
+
+/// Something wrong with one instruction of a microcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    /// The instruction never sets `RESET_MICRO`, so it runs until the micro counter wraps
+    NoResetMicro,
+    /// The step has controls, but an earlier step of the instruction sets `RESET_MICRO`
+    Unreachable { reset_at: usize },
+    /// More than one output drives the data bus, given as the bits of `Controls`
+    BusContention { sources: u32 },
+    /// `FLAGS_IN` latches the flags without `ADDER_OUT`, so they don't match the bus
+    FlagsWithoutAdder,
+    /// The step has controls, but the micro counter wraps after `steps` microsteps
+    PastLastStep { steps: usize },
+}
+
+/// A problem found by `lint`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Lint {
+    pub version: Version,
+    pub opcode: u8,
+    /// The microstep with the problem, if it's a single step
+    pub micro: Option<usize>,
+    pub kind: LintKind,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = isa::syntax(self.version, self.opcode).map_or("?", |(mnemonic, _)| mnemonic);
+        write!(f, "{} (0x{:02x})", mnemonic, self.opcode)?;
+        if let Some(micro) = self.micro {
+            write!(f, " micro {}", micro)?;
+        }
+        write!(f, ": ")?;
+
+        match self.kind {
+            LintKind::NoResetMicro => write!(
+                f,
+                "never sets RESET_MICRO, so it relies on the micro counter wrapping"
+            ),
+            LintKind::Unreachable { reset_at } => {
+                write!(f, "never runs, as RESET_MICRO is set at micro {}", reset_at)
+            }
+            LintKind::BusContention { sources } => write!(
+                f,
+                "bus contention between {}",
+                microcode::format_controls(self.version, sources)
+            ),
+            LintKind::FlagsWithoutAdder => write!(f, "FLAGS_IN is set without ADDER_OUT"),
+            LintKind::PastLastStep { steps } => write!(
+                f,
+                "never runs, as the micro counter wraps after {} microsteps",
+                steps
+            ),
+        }
+    }
+}
+
+/// Check every instruction of a microcode, returning the problems in opcode order
+#[must_use]
+pub fn lint(microcode: &Microcode) -> Vec<Lint> {
+    let version = microcode.version();
+    let bit = |name: &str| {
+        microcode::control_names(version)
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map_or(0, |(_, bit)| bit)
+    };
+    let (reset_micro, flags_in, adder_out) =
+        (bit("RESET_MICRO"), bit("FLAGS_IN"), bit("ADDER_OUT"));
+
+    let mut lints = Vec::new();
+    for (opcode, steps) in microcode.iter() {
+        let mut lint = |micro, kind| {
+            lints.push(Lint {
+                version,
+                opcode,
+                micro,
+                kind,
+            });
+        };
+
+        // v1 has no RESET_MICRO, so every instruction takes the full cycle of `microcode.steps()`
+        let reset_at = steps.iter().position(|s| s & reset_micro != 0);
+        if reset_micro != 0 && reset_at.is_none() {
+            lint(None, LintKind::NoResetMicro);
+        }
+
+        for (micro, controls) in steps.iter().enumerate() {
+            if *controls != 0 && micro >= microcode.steps() {
+                lint(
+                    Some(micro),
+                    LintKind::PastLastStep {
+                        steps: microcode.steps(),
+                    },
+                );
+            }
+            if let Some(reset_at) = reset_at.filter(|r| micro > *r && *controls != 0) {
+                lint(Some(micro), LintKind::Unreachable { reset_at });
+            }
+            if let Some(sources) = microcode::bus_contention(version, *controls) {
+                lint(Some(micro), LintKind::BusContention { sources });
+            }
+            if controls & flags_in != 0 && controls & adder_out == 0 {
+                lint(Some(micro), LintKind::FlagsWithoutAdder);
+            }
+        }
+    }
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v3::Controls as C;
+
+    /// The lints of one instruction of a custom microcode
+    fn lints(version: Version, text: &str, opcode: u8) -> Vec<Lint> {
+        lint(&Microcode::parse(version, text).unwrap())
+            .into_iter()
+            .filter(|l| l.opcode == opcode)
+            .collect()
+    }
+
+    #[test]
+    fn builtin_microcode() {
+        assert!(lint(&Microcode::builtin(Version::V1)).is_empty());
+        assert!(lint(&Microcode::builtin(Version::V3)).is_empty());
+        // `Addm` and `Subm` reset at micro 3, before their adder step
+        let unreachable = |opcode| Lint {
+            version: Version::V2,
+            opcode,
+            micro: Some(4),
+            kind: LintKind::Unreachable { reset_at: 3 },
+        };
+        assert_eq!(
+            lint(&Microcode::builtin(Version::V2)),
+            [unreachable(0x07), unreachable(0x0a)]
+        );
+        assert_eq!(
+            unreachable(0x07).to_string(),
+            "addm (0x07) micro 4: never runs, as RESET_MICRO is set at micro 3"
+        );
+    }
+
+    #[test]
+    fn custom_microcode() {
+        let kinds = |text, opcode| {
+            lints(Version::V3, text, opcode)
+                .into_iter()
+                .map(|l| (l.micro, l.kind))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds("0x00 0: A_OUT | B_OUT\n0x00 1: RESET_MICRO", 0x00),
+            [(
+                Some(0),
+                LintKind::BusContention {
+                    sources: (C::A_OUT | C::B_OUT).bits()
+                }
+            )]
+        );
+        assert_eq!(
+            kinds("0x00 2: FLAGS_IN | RESET_MICRO", 0x00),
+            [(Some(2), LintKind::FlagsWithoutAdder)]
+        );
+        assert!(kinds("0x10 2: ADDER_OUT | A_IN | FLAGS_IN | RESET_MICRO", 0x10).is_empty());
+        assert_eq!(
+            kinds("0x00 2: A_IN", 0x00),
+            [(None, LintKind::NoResetMicro)]
+        );
+    }
+
+    #[test]
+    fn v1_fits_the_cycle() {
+        let steps = Microcode::builtin(Version::V1).steps();
+        assert_eq!(steps, 5);
+        // Nothing needs RESET_MICRO, as every instruction takes the full cycle
+        assert!(lints(Version::V1, "0x01 4: A_IN", 0x01).is_empty());
+        // A step past the cycle can't be in a `Microcode` at all
+        assert!(Microcode::parse(Version::V1, "0x01 5: A_IN").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! Errors at a line of a text file, shared by the parsers of sources, images, symbols and microcode
+
+use std::{error, fmt, path::PathBuf};
+
+/// An error of kind `K`, with where it occurred
+///
+/// Shown as `file:line: kind`, leaving out whichever of the file and line isn't known.
+#[derive(Debug)]
+pub struct Located<K> {
+    /// The file the error occurred in, if the text came from a file
+    pub file: Option<PathBuf>,
+    /// The 1-based line the error occurred on, or 0 if it isn't tied to a line
+    pub line: usize,
+    pub kind: K,
+}
+
+impl<K> Located<K> {
+    /// An error at a line of text that didn't come from a file
+    #[must_use]
+    pub fn new(line: usize, kind: K) -> Self {
+        Self {
+            file: None,
+            line,
+            kind,
+        }
+    }
+
+    /// The same error, in the file `file`
+    #[must_use]
+    pub fn in_file(self, file: impl Into<PathBuf>) -> Self {
+        Self {
+            file: Some(file.into()),
+            ..self
+        }
+    }
+}
+
+impl<K: fmt::Display> fmt::Display for Located<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}: {}", file.display(), self.kind),
+            (Some(file), line) => write!(f, "{}:{}: {}", file.display(), line, self.kind),
+            (None, 0) => write!(f, "{}", self.kind),
+            (None, line) => write!(f, "line {}: {}", line, self.kind),
+        }
+    }
+}
+
+// The kind is already part of the message, so the cause is whatever caused the kind
+impl<K: error::Error> error::Error for Located<K> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.kind.source()
+    }
+}
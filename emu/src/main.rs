@@ -1,15 +1,47 @@
-use clap::{ArgEnum, Parser};
+use clap::{AppSettings, ArgEnum, Parser, Subcommand};
 use fs_err as fs;
-use puttpc_emu::{v1, v2, Machine};
-use std::{error::Error, io, path::PathBuf};
+use puttpc_emu::{
+    asm, disasm, golden, image, lint, region::Region, rom, symbols, trace::Tracer, v1, v2, v3,
+    vcd::Vcd, world::Layout, Limits, Machine, Microcode, Outcome, Register, Rom, Snapshot, Symbols,
+    Version,
+};
+use std::{
+    error::Error,
+    hash::Hash,
+    io::{self, BufWriter},
+    path::PathBuf,
+    process,
+};
+
+mod debugger;
+mod tui;
 
 #[derive(Debug, Parser)]
 #[clap(name = "PuttPc Emulator", about, long_about = None)]
+#[clap(setting(AppSettings::SubcommandsNegateReqs))]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The version of PuttPc to emulate
-    #[clap(short, long, arg_enum, default_value_t)]
+    // clap strips args with the id `version` from subcommands, so this needs another id
+    #[clap(
+        name = "emulated-version",
+        short = 'v',
+        long = "version",
+        value_name = "VERSION"
+    )]
+    #[clap(arg_enum, default_value_t, global = true)]
     version: Version,
 
+    /// Run with microcode loaded from a file instead of the built-in microcode
+    #[clap(long, value_name = "FILE", global = true)]
+    microcode: Option<PathBuf>,
+
+    /// Fault when more than one output drives the data bus at once
+    #[clap(long, global = true)]
+    check_bus: bool,
+
     /// Suppress printing of output
     #[clap(long)]
     no_output: bool,
@@ -22,21 +54,222 @@ struct Cli {
     #[clap(long)]
     pause: bool,
 
+    /// What counts as a step for --state, --pause and --trace
+    #[clap(long, arg_enum, default_value_t)]
+    granularity: Granularity,
+
+    /// Stop after this many steps
+    #[clap(long)]
+    max_steps: Option<u64>,
+
+    /// Stop after this many instructions
+    #[clap(long)]
+    max_instructions: Option<u64>,
+
+    /// Stop if the machine gets stuck in a loop
+    #[clap(long)]
+    detect_loops: bool,
+
+    /// Write a waveform of every step to a Value Change Dump file
+    #[clap(long, value_name = "FILE")]
+    vcd: Option<PathBuf>,
+
+    /// Print a trace of every step to stdout, with output in the trace instead of printed
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    trace: Option<TraceFormat>,
+
+    /// Resume from a state saved with --save-state instead of starting from an input
+    #[clap(long, value_name = "FILE", conflicts_with = "input")]
+    load_state: Option<PathBuf>,
+
+    /// Save the state of the machine when it stops
+    #[clap(long, value_name = "FILE")]
+    save_state: Option<PathBuf>,
+
+    /// The format of the input [default: from its extension, or raw]
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    format: Option<image::Format>,
+
+    /// A symbol file or listing of the input, for labels and source lines in --state and --trace
+    #[clap(long, value_name = "FILE")]
+    symbols: Vec<PathBuf>,
+
     /// The input to feed into the computer
-    input: PathBuf,
+    #[clap(required_unless_present = "load-state")]
+    input: Option<PathBuf>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
-enum Version {
-    V1,
-    V2,
-    V3,
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum Granularity {
+    /// A whole instruction
+    Instr,
+    /// A single microstep
+    #[default]
+    Micro,
 }
 
-impl Default for Version {
-    fn default() -> Self {
-        Self::V3
-    }
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum TraceFormat {
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Assemble a program into a memory image
+    Assemble {
+        /// The assembly source
+        source: PathBuf,
+
+        /// Where to write the image [default: the source with a .bin extension]
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Also write a symbol file of every label
+        #[clap(long, value_name = "FILE")]
+        symbols: Option<PathBuf>,
+
+        /// Also write a listing of the source line that emitted each byte
+        #[clap(long, value_name = "FILE")]
+        listing: Option<PathBuf>,
+    },
+
+    /// Disassemble a memory image
+    Disasm {
+        /// The memory image
+        input: PathBuf,
+
+        /// The format of the image [default: from its extension, or raw]
+        #[clap(long, arg_enum)]
+        format: Option<image::Format>,
+
+        /// A symbol file or listing of the image, to show labels and source lines
+        #[clap(long, value_name = "FILE")]
+        symbols: Vec<PathBuf>,
+    },
+
+    /// Print the built-in microcode, as a starting point for --microcode
+    Microcode,
+
+    /// Check the microcode, from --microcode or built in, for bugs
+    Lint,
+
+    /// Export a ROM as the bits to set at each address, for building it in Minecraft
+    Export {
+        /// Which ROM to export
+        #[clap(arg_enum)]
+        rom: RomKind,
+
+        /// The memory image, when exporting the program ROM
+        #[clap(required_if_eq("rom", "program"))]
+        input: Option<PathBuf>,
+
+        /// The format of the image [default: from its extension, or raw]
+        #[clap(long, arg_enum, value_name = "FORMAT")]
+        input_format: Option<image::Format>,
+
+        /// The format to write
+        #[clap(short, long, arg_enum, default_value_t)]
+        format: rom::Format,
+
+        /// Where to write the ROM [default: stdout]
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write a program into the in-game memory, as a structure file or a function
+    Place {
+        /// The memory image
+        input: PathBuf,
+
+        /// The format of the image [default: from its extension, or raw]
+        #[clap(long, arg_enum, value_name = "FORMAT")]
+        input_format: Option<image::Format>,
+
+        /// A JSON file giving the position of each bit of memory
+        #[clap(short, long, value_name = "FILE")]
+        layout: PathBuf,
+
+        /// What to write [default: from the extension of the output]
+        #[clap(short, long, arg_enum)]
+        format: Option<PlaceFormat>,
+
+        /// Where to write the structure or function
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Read the in-game memory out of a region file into a memory image
+    Extract {
+        /// The region file holding the memory, named like r.0.0.mca
+        region: PathBuf,
+
+        /// A JSON file giving the position of each bit of memory
+        #[clap(short, long, value_name = "FILE")]
+        layout: PathBuf,
+
+        /// Where to write the memory image
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+
+    /// Run a program in an interactive debugger
+    Debug {
+        /// The input to feed into the computer
+        input: PathBuf,
+
+        /// The format of the input [default: from its extension, or raw]
+        #[clap(long, arg_enum)]
+        format: Option<image::Format>,
+
+        /// A symbol file or listing of the input, to show labels and source lines
+        #[clap(long, value_name = "FILE")]
+        symbols: Vec<PathBuf>,
+    },
+
+    /// Run a program in a full-screen terminal interface
+    Tui {
+        /// The input to feed into the computer
+        input: PathBuf,
+
+        /// The format of the input [default: from its extension, or raw]
+        #[clap(long, arg_enum)]
+        format: Option<image::Format>,
+
+        /// A symbol file or listing of the input, to show labels and source lines
+        #[clap(long, value_name = "FILE")]
+        symbols: Vec<PathBuf>,
+    },
+
+    /// Check the output of every program with `; expect:` comments
+    ///
+    /// Each program runs on the version named by a directory it's in, such as asm/v2, or else
+    /// on --version.
+    Test {
+        /// Programs, or directories to search for .S files, skipping hidden and `target` ones
+        #[clap(default_value = ".")]
+        paths: Vec<PathBuf>,
+
+        /// The most steps each program may run
+        #[clap(long, default_value_t = 100_000)]
+        max_steps: u64,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum RomKind {
+    /// The microcode, from --microcode or built in
+    Microcode,
+    /// A program image
+    Program,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ArgEnum)]
+enum PlaceFormat {
+    /// A structure file, for a structure block
+    Nbt,
+    /// A datapack function of setblock commands
+    Mcfunction,
 }
 
 fn main() {
@@ -47,43 +280,369 @@ fn main() {
             eprintln!("  Cause: {}", s);
             e = s;
         }
+        process::exit(1);
     }
 }
 
 fn main_err() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    let input = fs::read(&cli.input)?;
+    match &cli.command {
+        Some(Command::Assemble {
+            source,
+            output,
+            symbols,
+            listing,
+        }) => {
+            let (image, labels) = asm::assemble_file_with_symbols(cli.version, source)?;
+            if let Some(path) = symbols {
+                fs::write(path, labels.symbol_file())?;
+            }
+            if let Some(path) = listing {
+                fs::write(path, labels.listing(&image))?;
+            }
+            let output = output
+                .clone()
+                .unwrap_or_else(|| source.with_extension("bin"));
+            fs::write(output, image)?;
+            return Ok(());
+        }
+        Some(Command::Disasm {
+            input,
+            format,
+            symbols,
+        }) => {
+            let image = image::load(input, *format)?;
+            let symbols = load_symbols(symbols)?;
+            for line in disasm::disassemble_with_symbols(cli.version, &image, &symbols) {
+                for label in symbols.labels_at(line.address) {
+                    println!("{}:", label);
+                }
+                println!("{}", line.with_source(&symbols));
+            }
+            return Ok(());
+        }
+        Some(Command::Microcode) => {
+            print!("{}", Microcode::builtin(cli.version));
+            return Ok(());
+        }
+        Some(Command::Lint) => {
+            let microcode = match &cli.microcode {
+                Some(path) => Microcode::load(cli.version, path)?,
+                None => Microcode::builtin(cli.version),
+            };
+            let lints = lint::lint(&microcode);
+            for l in &lints {
+                println!("{}", l);
+            }
+            return match lints.len() {
+                0 => Ok(()),
+                n => Err(format!("found {} problems in the microcode", n).into()),
+            };
+        }
+        Some(Command::Export {
+            rom,
+            input,
+            input_format,
+            format,
+            output,
+        }) => {
+            let rom = match (rom, input) {
+                (RomKind::Microcode, _) => match &cli.microcode {
+                    Some(path) => Rom::microcode(&Microcode::load(cli.version, path)?),
+                    None => Rom::microcode(&Microcode::builtin(cli.version)),
+                },
+                (RomKind::Program, Some(input)) => {
+                    Rom::program(cli.version, &image::load(input, *input_format)?)?
+                }
+                (RomKind::Program, None) => unreachable!("clap requires an input for programs"),
+            };
+            let rom = rom.to_format(*format);
+            match output {
+                Some(output) => fs::write(output, rom)?,
+                None => print!("{}", rom),
+            }
+            return Ok(());
+        }
+        Some(Command::Place {
+            input,
+            input_format,
+            layout,
+            format,
+            output,
+        }) => {
+            let layout: Layout = serde_json::from_str(&fs::read_to_string(layout)?)?;
+            let rom = Rom::program(cli.version, &image::load(input, *input_format)?)?;
+            let extension = output.extension().and_then(|e| e.to_str());
+            match format.or_else(|| PlaceFormat::from_str(extension?, true).ok()) {
+                Some(PlaceFormat::Nbt) => fs::write(output, layout.structure(&rom)?)?,
+                Some(PlaceFormat::Mcfunction) => fs::write(output, layout.mcfunction(&rom))?,
+                None => {
+                    let e = format!(
+                        "can't tell the format of {}, use --format",
+                        output.display()
+                    );
+                    return Err(e.into());
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Extract {
+            region,
+            layout,
+            output,
+        }) => {
+            let layout: Layout = serde_json::from_str(&fs::read_to_string(layout)?)?;
+            let mut region = Region::open(region)?;
+            fs::write(
+                output,
+                layout.read_memory(&mut region, cli.version.memory_size())?,
+            )?;
+            return Ok(());
+        }
+        Some(Command::Debug {
+            input,
+            format,
+            symbols,
+        }) => {
+            let input = image::load(input, *format)?;
+            let symbols = load_symbols(symbols)?;
+            return match cli.version {
+                Version::V1 => debugger::debug(
+                    configure(v1::PuttPc::with_input(&input)?, &cli)?,
+                    symbols,
+                    limits(&cli),
+                ),
+                Version::V2 => debugger::debug(
+                    configure(v2::PuttPc::with_input(&input)?, &cli)?,
+                    symbols,
+                    limits(&cli),
+                ),
+                Version::V3 => debugger::debug(
+                    configure(v3::PuttPc::with_input(&input)?, &cli)?,
+                    symbols,
+                    limits(&cli),
+                ),
+            };
+        }
+        Some(Command::Tui {
+            input,
+            format,
+            symbols,
+        }) => {
+            let input = image::load(input, *format)?;
+            let symbols = load_symbols(symbols)?;
+            return match cli.version {
+                Version::V1 => tui::tui(configure(v1::PuttPc::with_input(&input)?, &cli)?, symbols),
+                Version::V2 => tui::tui(configure(v2::PuttPc::with_input(&input)?, &cli)?, symbols),
+                Version::V3 => tui::tui(configure(v3::PuttPc::with_input(&input)?, &cli)?, symbols),
+            };
+        }
+        Some(Command::Test { paths, max_steps }) => {
+            let mut sources = Vec::new();
+            for path in paths {
+                asm::find_sources(path, &mut sources)?;
+            }
+
+            let (mut passed, mut failed) = (0, 0);
+            for path in sources {
+                let expected = match golden::expected_output(&fs::read_to_string(&path)?) {
+                    Ok(Some(expected)) => expected,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("FAIL {}\n  {}", path.display(), e);
+                        failed += 1;
+                        continue;
+                    }
+                };
+                let version = asm::version_of(&path).unwrap_or(cli.version);
+                let microcode = match &cli.microcode {
+                    Some(microcode) => match Microcode::load(version, microcode) {
+                        Ok(microcode) => Some(microcode),
+                        Err(e) => {
+                            println!("FAIL {}\n  {}", path.display(), e);
+                            failed += 1;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                match golden::check(
+                    &path,
+                    version,
+                    &expected,
+                    *max_steps,
+                    microcode.as_ref(),
+                    cli.check_bus,
+                ) {
+                    Ok(()) => {
+                        println!("PASS {}", path.display());
+                        passed += 1;
+                    }
+                    Err(e) => {
+                        println!("FAIL {}\n  {}", path.display(), e);
+                        failed += 1;
+                    }
+                }
+            }
+
+            println!("{} passed, {} failed", passed, failed);
+            if failed > 0 {
+                return Err(format!("{} of {} tests failed", failed, passed + failed).into());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if let Some(path) = &cli.load_state {
+        // The snapshot knows its own version, so --version doesn't apply
+        return match serde_json::from_str(&fs::read_to_string(path)?)? {
+            Snapshot::V1(machine) => run(machine, &cli),
+            Snapshot::V2(machine) => run(machine, &cli),
+            Snapshot::V3(machine) => run(machine, &cli),
+        };
+    }
+
+    let input = cli
+        .input
+        .as_ref()
+        .expect("input is required without a subcommand or saved state");
+    let input = image::load(input, cli.format)?;
 
     match cli.version {
-        Version::V1 => run(v1::PuttPc::with_input(&input), &cli),
-        Version::V2 => run(v2::PuttPc::with_input(&input), &cli),
-        Version::V3 => todo!(), //run(v3::PuttPc::with_input(&input), &cli),
+        Version::V1 => run(v1::PuttPc::with_input(&input)?, &cli),
+        Version::V2 => run(v2::PuttPc::with_input(&input)?, &cli),
+        Version::V3 => run(v3::PuttPc::with_input(&input)?, &cli),
     }
+}
 
-    Ok(())
+/// Read and combine symbol files and listings
+fn load_symbols(paths: &[PathBuf]) -> Result<Symbols, symbols::Error> {
+    let mut symbols = Symbols::default();
+    for path in paths {
+        symbols.extend(Symbols::load(path)?);
+    }
+    Ok(symbols)
 }
 
-fn run(mut machine: impl Machine, cli: &Cli) {
+/// Where the counter is in the source, like `loop+1: txb`, if symbols say
+fn source_position(symbols: &Symbols, machine: &impl Machine) -> Option<String> {
+    let counter = usize::from(machine.regs()[Register::Counter as usize]);
+    match (symbols.describe(counter), symbols.source_at(counter)) {
+        (Some(label), Some(source)) => Some(format!("{}: {}", label, source)),
+        (Some(label), None) => Some(label),
+        (None, Some(source)) => Some(source.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// Apply --microcode and --check-bus to a machine, keeping what a loaded snapshot had otherwise
+fn configure<M: Machine>(mut machine: M, cli: &Cli) -> Result<M, Box<dyn Error>> {
+    if cli.check_bus {
+        machine.set_check_bus(true);
+    }
+    if let Some(path) = &cli.microcode {
+        machine.set_microcode(Microcode::load(machine.version(), path)?)?;
+    }
+    Ok(machine)
+}
+
+/// The limits given by --max-steps, --max-instructions and --detect-loops
+fn limits(cli: &Cli) -> Limits {
+    Limits {
+        max_steps: cli.max_steps,
+        max_instructions: cli.max_instructions,
+        detect_loops: cli.detect_loops,
+    }
+}
+
+fn run<M>(machine: M, cli: &Cli) -> Result<(), Box<dyn Error>>
+where
+    M: Machine<Output = u8> + Clone + Eq + Hash + Into<Snapshot>,
+{
+    let mut machine = configure(machine, cli)?;
+    let symbols = load_symbols(&cli.symbols)?;
+
     // a buffer for stdin.read_line. data isn't used
     let mut s = String::new();
 
-    while !machine.is_halted() {
+    let limits = limits(cli);
+    let mut vcd = match &cli.vcd {
+        Some(path) => Some(Vcd::new(BufWriter::new(fs::File::create(path)?), &machine)?),
+        None => None,
+    };
+    let mut tracer = cli
+        .trace
+        .map(|_| Tracer::new(cli.granularity == Granularity::Instr).with_symbols(symbols.clone()));
+
+    let run = machine.run_bounded_with(limits, |machine| -> Result<_, Box<dyn Error>> {
+        if let Some(vcd) = &mut vcd {
+            vcd.record(machine)?;
+        }
+        if let Some(tracer) = &mut tracer {
+            tracer.before(machine);
+        }
         let out = machine.step();
+        let boundary = cli.granularity == Granularity::Micro
+            || machine.micro() == 0
+            || machine.is_halted()
+            || out.is_err();
 
-        if cli.state {
-            println!("{}", machine);
+        if cli.state && boundary {
+            print!("{}", machine);
+            if let Some(position) = source_position(&symbols, machine) {
+                println!("Source\n  {}", position);
+            }
+            println!();
         }
 
+        if let Some(tracer) = &mut tracer {
+            let output = out.as_ref().ok().copied().flatten();
+            if let Some(event) = tracer.after(machine, output) {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+        }
+
+        let out = out?;
         if let Some(out) = out {
-            if !cli.no_output {
+            if !cli.no_output && tracer.is_none() {
                 println!("Output: 0x{:02x}", out);
             }
         }
 
-        if cli.pause {
+        if cli.pause && boundary {
             println!("Press Enter to continue");
-            io::stdin().read_line(&mut s).unwrap();
+            io::stdin().read_line(&mut s)?;
         }
+        Ok(out)
+    });
+
+    if let Some(mut vcd) = vcd {
+        vcd.record(&machine)?;
+        vcd.finish()?;
+    }
+    if let Some(path) = &cli.save_state {
+        let snapshot: Snapshot = machine.into();
+        fs::write(path, serde_json::to_string_pretty(&snapshot)?)?;
     }
+
+    let run = run?;
+    match run.outcome {
+        Outcome::Halted => {}
+        Outcome::BudgetExhausted => {
+            eprintln!(
+                "Stopped after {} steps ({} instructions)",
+                run.steps, run.instructions
+            );
+        }
+        Outcome::Looping => {
+            eprintln!(
+                "Stopped after {} steps: the machine is in an infinite loop",
+                run.steps
+            );
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,404 @@
+//! Microcode ROMs as data, so they can be loaded from a file instead of compiled in
+//!
+//! The text format has one line per microstep, `<opcode> <step>: <controls>`, with the controls
+//! named as in the version's `Controls` and separated by `|`. Microsteps that aren't listed
+//! have no controls, and `#` starts a comment. `Display` writes the same format.
+
+use crate::{
+    isa::{self, Operand},
+    located::Located,
+    v1, v2, v3, Version,
+};
+use fs_err as fs;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    error, fmt,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    Syntax(String),
+    UnknownOpcode(u8),
+    UnknownControl(String),
+    StepOutOfRange { step: usize, steps: usize },
+    DuplicateStep { opcode: u8, step: usize },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "could not read microcode"),
+            Self::Syntax(s) => write!(f, "syntax error: {}", s),
+            Self::UnknownOpcode(o) => write!(f, "no instruction has opcode 0x{:02x}", o),
+            Self::UnknownControl(c) => write!(f, "unknown control `{}`", c),
+            Self::StepOutOfRange { step, steps } => {
+                write!(f, "microstep {} is out of range, there are {}", step, steps)
+            }
+            Self::DuplicateStep { opcode, step } => {
+                write!(
+                    f,
+                    "microstep {} of opcode 0x{:02x} is given twice",
+                    step, opcode
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub type Error = Located<ErrorKind>;
+
+/// A microcode ROM: the bits of `Controls` for each microstep of each instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Microcode {
+    version: Version,
+    table: BTreeMap<u8, Vec<u32>>,
+}
+
+// Machines are hashed for loop detection, which only ever compares a machine with itself, so
+// hashing the whole table every instruction would be wasted work
+impl Hash for Microcode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.version.hash(state);
+    }
+}
+
+impl Microcode {
+    /// The microcode compiled into the emulator
+    #[must_use]
+    pub fn builtin(version: Version) -> Self {
+        let steps = steps(version);
+        let table = isa::rules(version)
+            .iter()
+            .map(|&(opcode, _, _)| {
+                let controls = (0..steps)
+                    .map(|micro| match version {
+                        Version::V1 => v1::Instruction::try_from(opcode)
+                            .map(|i| i.controls(micro).bits())
+                            .unwrap_or_default(),
+                        Version::V2 => v2::Instruction::try_from(opcode)
+                            .map(|i| i.controls(micro).bits())
+                            .unwrap_or_default(),
+                        Version::V3 => v3::Instruction::try_from(opcode)
+                            .map(|i| i.controls(micro).bits())
+                            .unwrap_or_default(),
+                    })
+                    .collect();
+                (opcode, controls)
+            })
+            .collect();
+        Self { version, table }
+    }
+
+    /// Parse microcode in the text format for the given version
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text isn't valid microcode for the version.
+    pub fn parse(version: Version, text: &str) -> Result<Self, Error> {
+        let steps = steps(version);
+        let names = control_names(version);
+        let mut table: BTreeMap<u8, Vec<Option<u32>>> = isa::rules(version)
+            .iter()
+            .map(|&(opcode, _, _)| (opcode, vec![None; steps]))
+            .collect();
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |kind| Error::new(i + 1, kind);
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (position, controls) = line
+                .split_once(':')
+                .ok_or_else(|| error(ErrorKind::Syntax("expected `:`".to_string())))?;
+            let (opcode, step) = match position.split_whitespace().collect::<Vec<_>>()[..] {
+                [opcode, step] => (opcode, step),
+                _ => {
+                    let e = format!("expected `<opcode> <step>`, found `{}`", position.trim());
+                    return Err(error(ErrorKind::Syntax(e)));
+                }
+            };
+            let opcode = parse_number(opcode)
+                .and_then(|o| u8::try_from(o).ok())
+                .ok_or_else(|| error(ErrorKind::Syntax(format!("invalid opcode `{}`", opcode))))?;
+            let step = parse_number(step)
+                .ok_or_else(|| error(ErrorKind::Syntax(format!("invalid step `{}`", step))))?;
+
+            let mut bits = 0;
+            for control in controls.split('|').map(str::trim).filter(|c| !c.is_empty()) {
+                bits |= names
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(control))
+                    .map(|(_, bit)| bit)
+                    .ok_or_else(|| error(ErrorKind::UnknownControl(control.to_string())))?;
+            }
+
+            let entry = table
+                .get_mut(&opcode)
+                .ok_or_else(|| error(ErrorKind::UnknownOpcode(opcode)))?
+                .get_mut(step)
+                .ok_or_else(|| error(ErrorKind::StepOutOfRange { step, steps }))?;
+            if entry.replace(bits).is_some() {
+                return Err(error(ErrorKind::DuplicateStep { opcode, step }));
+            }
+        }
+
+        let table = table
+            .into_iter()
+            .map(|(opcode, steps)| {
+                (
+                    opcode,
+                    steps.into_iter().map(Option::unwrap_or_default).collect(),
+                )
+            })
+            .collect();
+        Ok(Self { version, table })
+    }
+
+    /// Load microcode for the given version from a file in the text format
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or isn't valid microcode for the version.
+    pub fn load(version: Version, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| Error {
+            file: Some(path.to_path_buf()),
+            line: 0,
+            kind: ErrorKind::Io(e),
+        })?;
+        Self::parse(version, &text).map_err(|e| e.in_file(path))
+    }
+
+    #[must_use]
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The number of microsteps each instruction has
+    #[must_use]
+    pub fn steps(&self) -> usize {
+        steps(self.version)
+    }
+
+    /// The bits of the controls for a microstep of an instruction, or 0 if there are none
+    #[must_use]
+    pub fn controls(&self, opcode: u8, micro: usize) -> u32 {
+        self.table
+            .get(&opcode)
+            .and_then(|steps| steps.get(micro))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every opcode with its controls for each microstep
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &[u32])> {
+        self.table
+            .iter()
+            .map(|(opcode, steps)| (*opcode, &steps[..]))
+    }
+
+    /// The names of the controls set in `bits`, separated by `|`
+    #[must_use]
+    pub fn format_controls(&self, bits: u32) -> String {
+        format_controls(self.version, bits)
+    }
+
+    /// The name and bit of every control
+    #[must_use]
+    pub fn control_names(&self) -> Vec<(String, u32)> {
+        control_names(self.version)
+    }
+}
+
+impl fmt::Display for Microcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# PuttPc {:?} microcode", self.version)?;
+        writeln!(f, "# <opcode> <step>: <controls>")?;
+        for (opcode, steps) in self.iter() {
+            writeln!(f)?;
+            if let Some((mnemonic, operands)) = isa::syntax(self.version, opcode) {
+                write!(f, "# {}", mnemonic)?;
+                for o in operands {
+                    match o {
+                        Operand::A => write!(f, " %a")?,
+                        Operand::B => write!(f, " %b")?,
+                        Operand::Value => write!(f, " <value>")?,
+                        Operand::Address => write!(f, " <address>")?,
+                    }
+                }
+                writeln!(f)?;
+            }
+            for (step, bits) in steps.iter().enumerate().filter(|(_, bits)| **bits != 0) {
+                writeln!(
+                    f,
+                    "0x{:02x} {}: {}",
+                    opcode,
+                    step,
+                    self.format_controls(*bits)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn steps(version: Version) -> usize {
+    match version {
+        Version::V1 => v1::MICRO_STEPS,
+        Version::V2 => v2::MICRO_STEPS,
+        Version::V3 => v3::MICRO_STEPS,
+    }
+}
+
+/// The names of the controls of a version set in `bits`, separated by `|`
+pub(crate) fn format_controls(version: Version, bits: u32) -> String {
+    control_names(version)
+        .into_iter()
+        .filter(|(_, bit)| bits & bit != 0)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// The outputs driving the data bus in `controls`, if there is more than one
+pub(crate) fn bus_contention(version: Version, controls: u32) -> Option<u32> {
+    let outputs = match version {
+        Version::V1 => v1::Controls::BUS_OUTPUTS.bits(),
+        Version::V2 => v2::Controls::BUS_OUTPUTS.bits(),
+        Version::V3 => v3::Controls::BUS_OUTPUTS.bits(),
+    };
+    let sources = controls & outputs;
+    (sources.count_ones() > 1).then_some(sources)
+}
+
+/// The name and bit of every control of a version
+pub(crate) fn control_names(version: Version) -> Vec<(String, u32)> {
+    (0..32)
+        .map(|i| 1 << i)
+        // The `Debug` of a single flag is its name
+        .filter_map(|bit| {
+            match version {
+                Version::V1 => v1::Controls::from_bits(bit).map(|c| format!("{:?}", c)),
+                Version::V2 => v2::Controls::from_bits(bit).map(|c| format!("{:?}", c)),
+                Version::V3 => v3::Controls::from_bits(bit).map(|c| format!("{:?}", c)),
+            }
+            .map(|name| (name, bit))
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{v2::Controls as C, Machine, MachineError};
+
+    fn parse_error(text: &str) -> Error {
+        Microcode::parse(Version::V2, text).unwrap_err()
+    }
+
+    #[test]
+    fn builtin_round_trips_through_text() {
+        for version in [Version::V1, Version::V2, Version::V3] {
+            let builtin = Microcode::builtin(version);
+            let parsed = Microcode::parse(version, &builtin.to_string()).unwrap();
+            assert_eq!(parsed, builtin, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn parses_controls() {
+        let microcode = Microcode::parse(
+            Version::V2,
+            "
+            # a comment
+            0x0e 2: a_out | OUTPUT_IN  # names ignore case
+            14 3: RESET_MICRO
+            0x0f 0:
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            microcode.controls(0x0e, 2),
+            (C::A_OUT | C::OUTPUT_IN).bits()
+        );
+        assert_eq!(microcode.controls(0x0e, 3), C::RESET_MICRO.bits());
+        assert_eq!(microcode.controls(0x0e, 0), 0);
+        assert_eq!(microcode.controls(0x0f, 0), 0);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for text in [
+            "0x0e 2 A_OUT",
+            "0x0e: A_OUT",
+            "0x0e 2 1: A_OUT",
+            "0x100 0:",
+            "x 0:",
+        ] {
+            let e = parse_error(text);
+            assert!(matches!(e.kind, ErrorKind::Syntax(_)), "{}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn semantic_errors() {
+        assert!(matches!(
+            parse_error("0x0e 0: A_OUT | NOT_A_CONTROL").kind,
+            ErrorKind::UnknownControl(c) if c == "NOT_A_CONTROL"
+        ));
+        assert!(matches!(
+            Microcode::parse(Version::V3, "0xfe 0:").unwrap_err().kind,
+            ErrorKind::UnknownOpcode(0xfe)
+        ));
+        assert!(matches!(
+            parse_error("0x0e 5:").kind,
+            ErrorKind::StepOutOfRange { step: 5, steps: 5 }
+        ));
+        let e = parse_error("0x0e 1: A_OUT\n\n0x0e 1: B_OUT");
+        assert_eq!(e.line, 3);
+        assert!(matches!(
+            e.kind,
+            ErrorKind::DuplicateStep {
+                opcode: 0x0e,
+                step: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn machines_reject_other_versions() {
+        let mut machine = crate::v2::PuttPc::with_input(&[]).unwrap();
+        assert_eq!(
+            machine.set_microcode(Microcode::builtin(Version::V3)),
+            Err(MachineError::WrongMicrocode {
+                machine: Version::V2,
+                microcode: Version::V3,
+            })
+        );
+        assert_eq!(machine.microcode().version(), Version::V2);
+    }
+}
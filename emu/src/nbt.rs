@@ -0,0 +1,249 @@
+//! Minecraft's Named Binary Tag format
+
+use std::io::{self, Read, Write};
+
+/// The most lists and compounds that can be nested, as in Minecraft, so that reading a
+/// malicious file can't overflow the stack
+const MAX_DEPTH: usize = 512;
+
+/// A single NBT value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    /// Every element must be the same type of tag
+    List(Vec<Tag>),
+    /// Named tags, in the order they're written
+    Compound(Vec<(String, Tag)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// The type id written before the tag
+    #[must_use]
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Byte(_) => 1,
+            Self::Short(_) => 2,
+            Self::Int(_) => 3,
+            Self::Long(_) => 4,
+            Self::Float(_) => 5,
+            Self::Double(_) => 6,
+            Self::ByteArray(_) => 7,
+            Self::String(_) => 8,
+            Self::List(_) => 9,
+            Self::Compound(_) => 10,
+            Self::IntArray(_) => 11,
+            Self::LongArray(_) => 12,
+        }
+    }
+
+    /// The tag with the given name, if this is a compound that has one
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Self::Compound(v) => v.iter().find(|(n, _)| n == name).map(|(_, t)| t),
+            _ => None,
+        }
+    }
+
+    /// Read the named root of an NBT file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading fails or the data isn't valid NBT.
+    pub fn read_root(r: &mut impl Read) -> io::Result<(String, Tag)> {
+        let id = read_array::<1>(r)?[0];
+        let name = read_string(r)?;
+        Ok((name, Self::read_payload(id, r, 0)?))
+    }
+
+    fn read_payload(id: u8, r: &mut impl Read, depth: usize) -> io::Result<Tag> {
+        if matches!(id, 9 | 10) && depth >= MAX_DEPTH {
+            return Err(invalid(format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        Ok(match id {
+            1 => Self::Byte(i8::from_be_bytes(read_array(r)?)),
+            2 => Self::Short(i16::from_be_bytes(read_array(r)?)),
+            3 => Self::Int(i32::from_be_bytes(read_array(r)?)),
+            4 => Self::Long(i64::from_be_bytes(read_array(r)?)),
+            5 => Self::Float(f32::from_be_bytes(read_array(r)?)),
+            6 => Self::Double(f64::from_be_bytes(read_array(r)?)),
+            7 => Self::ByteArray(read_vec(r, |r| Ok(i8::from_be_bytes(read_array(r)?)))?),
+            8 => Self::String(read_string(r)?),
+            9 => {
+                let id = read_array::<1>(r)?[0];
+                Self::List(read_vec(r, |r| Self::read_payload(id, r, depth + 1))?)
+            }
+            10 => {
+                let mut v = Vec::new();
+                loop {
+                    let id = read_array::<1>(r)?[0];
+                    if id == 0 {
+                        break;
+                    }
+                    let name = read_string(r)?;
+                    v.push((name, Self::read_payload(id, r, depth + 1)?));
+                }
+                Self::Compound(v)
+            }
+            11 => Self::IntArray(read_vec(r, |r| Ok(i32::from_be_bytes(read_array(r)?)))?),
+            12 => Self::LongArray(read_vec(r, |r| Ok(i64::from_be_bytes(read_array(r)?)))?),
+            _ => return Err(invalid(format!("unknown tag type {}", id))),
+        })
+    }
+
+    /// Write the tag as the named root of an NBT file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing fails.
+    pub fn write_root(&self, name: &str, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&[self.id()])?;
+        write_string(name, w)?;
+        self.write_payload(w)
+    }
+
+    fn write_payload(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::Byte(v) => w.write_all(&v.to_be_bytes()),
+            Self::Short(v) => w.write_all(&v.to_be_bytes()),
+            Self::Int(v) => w.write_all(&v.to_be_bytes()),
+            Self::Long(v) => w.write_all(&v.to_be_bytes()),
+            Self::Float(v) => w.write_all(&v.to_be_bytes()),
+            Self::Double(v) => w.write_all(&v.to_be_bytes()),
+            Self::ByteArray(v) => {
+                write_len(v.len(), w)?;
+                v.iter().try_for_each(|b| w.write_all(&b.to_be_bytes()))
+            }
+            Self::String(s) => write_string(s, w),
+            Self::List(v) => {
+                // An empty list has no elements to take the type from, so it's a list of End
+                w.write_all(&[v.first().map_or(0, Tag::id)])?;
+                write_len(v.len(), w)?;
+                v.iter().try_for_each(|t| t.write_payload(w))
+            }
+            Self::Compound(v) => {
+                for (name, t) in v {
+                    w.write_all(&[t.id()])?;
+                    write_string(name, w)?;
+                    t.write_payload(w)?;
+                }
+                w.write_all(&[0])
+            }
+            Self::IntArray(v) => {
+                write_len(v.len(), w)?;
+                v.iter().try_for_each(|i| w.write_all(&i.to_be_bytes()))
+            }
+            Self::LongArray(v) => {
+                write_len(v.len(), w)?;
+                v.iter().try_for_each(|l| w.write_all(&l.to_be_bytes()))
+            }
+        }
+    }
+}
+
+fn write_len(len: usize, w: &mut impl Write) -> io::Result<()> {
+    let len = i32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too long for NBT"))?;
+    w.write_all(&len.to_be_bytes())
+}
+
+fn write_string(s: &str, w: &mut impl Write) -> io::Result<()> {
+    // Close enough to Java's modified UTF-8 for the block names and keys written here
+    let len = u16::try_from(s.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "string too long for NBT"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_vec<R: Read, T>(
+    r: &mut R,
+    mut element: impl FnMut(&mut R) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let len = i32::from_be_bytes(read_array(r)?);
+    let len = usize::try_from(len).map_err(|_| invalid(format!("negative length {}", len)))?;
+    (0..len).map(|_| element(r)).collect()
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let len = u16::from_be_bytes(read_array(r)?);
+    let mut buf = vec![0; usize::from(len)];
+    r.read_exact(&mut buf)?;
+    // Modified UTF-8 only differs for nulls and supplementary characters, so this is lossy at worst
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8]) -> io::Result<(String, Tag)> {
+        Tag::read_root(&mut &bytes[..])
+    }
+
+    #[test]
+    fn round_trips() {
+        let tag = Tag::Compound(vec![
+            ("byte".to_string(), Tag::Byte(-1)),
+            ("short".to_string(), Tag::Short(300)),
+            ("int".to_string(), Tag::Int(-70000)),
+            ("long".to_string(), Tag::Long(1 << 40)),
+            ("float".to_string(), Tag::Float(0.5)),
+            ("double".to_string(), Tag::Double(-0.25)),
+            ("bytes".to_string(), Tag::ByteArray(vec![1, -2])),
+            ("string".to_string(), Tag::String("redstone".to_string())),
+            (
+                "list".to_string(),
+                Tag::List(vec![Tag::Int(1), Tag::Int(2)]),
+            ),
+            ("empty".to_string(), Tag::List(Vec::new())),
+            ("ints".to_string(), Tag::IntArray(vec![3, -4])),
+            ("longs".to_string(), Tag::LongArray(vec![5, -6])),
+        ]);
+        let mut bytes = Vec::new();
+        tag.write_root("root", &mut bytes).unwrap();
+        assert_eq!(read(&bytes).unwrap(), ("root".to_string(), tag));
+    }
+
+    #[test]
+    fn malformed_tags_are_errors() {
+        let invalid_data =
+            |bytes: &[u8]| read(bytes).unwrap_err().kind() == io::ErrorKind::InvalidData;
+        // A list of ints with a length of -1
+        assert!(invalid_data(&[9, 0, 0, 3, 0xff, 0xff, 0xff, 0xff]));
+        assert!(invalid_data(&[13, 0, 0]));
+        // A long array of 2^31 - 1 elements fails at the end of the data, instead of allocating them
+        let err = read(&[12, 0, 0, 0x7f, 0xff, 0xff, 0xff, 0, 0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        // Lists of lists, each with one element
+        let mut bytes = vec![9, 0, 0];
+        for _ in 0..=MAX_DEPTH {
+            bytes.extend([9, 0, 0, 0, 1]);
+        }
+        let err = read(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("nested"), "{}", err);
+    }
+}
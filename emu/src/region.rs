@@ -0,0 +1,410 @@
+//! Reading blocks out of Minecraft's region (`.mca`) files
+//!
+//! Chunks are read in the Anvil format of 1.16 onwards, both the `Level`/`Sections` layout
+//! used until 1.17 and the `sections`/`block_states` layout of 1.18 onwards.
+
+use crate::{nbt::Tag, world::Block};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use fs_err as fs;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    io::{self, Read},
+    path::Path,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file name isn't `r.<x>.<z>.mca`, so the region's position is unknown
+    UnknownPosition(String),
+    OutsideRegion {
+        pos: [i32; 3],
+    },
+    MissingChunk {
+        x: i32,
+        z: i32,
+    },
+    UnsupportedCompression(u8),
+    Malformed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "could not read region"),
+            Self::UnknownPosition(name) => {
+                write!(
+                    f,
+                    "can't tell the region's position from its name `{}`",
+                    name
+                )
+            }
+            Self::OutsideRegion { pos: [x, y, z] } => {
+                write!(f, "block at {} {} {} is outside of the region", x, y, z)
+            }
+            Self::MissingChunk { x, z } => write!(f, "chunk {} {} hasn't been generated", x, z),
+            Self::UnsupportedCompression(c) => write!(f, "unsupported chunk compression {}", c),
+            Self::Malformed(s) => write!(f, "malformed chunk: {}", s),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The blocks of one 16×16×16 section of a chunk
+struct Section {
+    palette: Vec<Block>,
+    data: Vec<i64>,
+}
+
+impl Section {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn block(&self, [x, y, z]: [i32; 3]) -> Option<&Block> {
+        if self.palette.len() == 1 {
+            return self.palette.first();
+        }
+        let bits = (usize::BITS - (self.palette.len() - 1).leading_zeros()).max(4) as usize;
+        let per_long = 64 / bits;
+        let index = ((y & 15) * 256 + (z & 15) * 16 + (x & 15)) as usize;
+        let long = *self.data.get(index / per_long)? as u64;
+        let state = (long >> (index % per_long * bits)) & ((1 << bits) - 1);
+        self.palette.get(state as usize)
+    }
+}
+
+/// A region file: 32×32 chunks, read as they're needed
+pub struct Region {
+    x: i32,
+    z: i32,
+    data: Vec<u8>,
+    /// The sections of each chunk that has been read, by their y
+    chunks: HashMap<(i32, i32), HashMap<i32, Section>>,
+}
+
+impl Region {
+    /// Open a region file, which must still be named `r.<x>.<z>.mca`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or its name isn't a region's.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (x, z) = match name.split('.').collect::<Vec<_>>()[..] {
+            ["r", x, z, "mca"] => x.parse().ok().zip(z.parse().ok()),
+            _ => None,
+        }
+        .ok_or(Error::UnknownPosition(name))?;
+        Ok(Self::new(x, z, fs::read(path)?))
+    }
+
+    /// A region from the contents of its file, at region `x` `z`
+    #[must_use]
+    pub fn new(x: i32, z: i32, data: Vec<u8>) -> Self {
+        Self {
+            x,
+            z,
+            data,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// The block at a position in the world
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the position isn't in this region, or its chunk can't be read.
+    pub fn block(&mut self, pos: [i32; 3]) -> Result<Block, Error> {
+        let [x, y, z] = pos;
+        let chunk = (x >> 4, z >> 4);
+        if (chunk.0 >> 5, chunk.1 >> 5) != (self.x, self.z) {
+            return Err(Error::OutsideRegion { pos });
+        }
+        if !self.chunks.contains_key(&chunk) {
+            let sections = self.read_chunk(chunk)?;
+            self.chunks.insert(chunk, sections);
+        }
+
+        let air = || Block {
+            name: "minecraft:air".to_string(),
+            properties: Default::default(),
+        };
+        Ok(self.chunks[&chunk]
+            .get(&(y >> 4))
+            .and_then(|s| s.block(pos))
+            .cloned()
+            .unwrap_or_else(air))
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    fn read_chunk(&self, (x, z): (i32, i32)) -> Result<HashMap<i32, Section>, Error> {
+        let malformed = |s: &str| Error::Malformed(s.to_string());
+
+        let entry = ((x & 31) + (z & 31) * 32) as usize * 4;
+        let location = self
+            .data
+            .get(entry..entry + 4)
+            .ok_or_else(|| malformed("region header is truncated"))?;
+        let sector = u32::from_be_bytes([0, location[0], location[1], location[2]]) as usize;
+        if sector == 0 {
+            return Err(Error::MissingChunk { x, z });
+        }
+
+        let start = sector * 4096;
+        let header = self
+            .data
+            .get(start..start + 5)
+            .ok_or_else(|| malformed("chunk is past the end of the region"))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let body = self
+            .data
+            .get(start + 5..start + 4 + len)
+            .ok_or_else(|| malformed("chunk is truncated"))?;
+        let mut reader: Box<dyn Read> = match header[4] {
+            1 => Box::new(GzDecoder::new(body)),
+            2 => Box::new(ZlibDecoder::new(body)),
+            3 => Box::new(body),
+            c => return Err(Error::UnsupportedCompression(c)),
+        };
+        let (_, chunk) = Tag::read_root(&mut reader)?;
+
+        let (sections, palette, data) = match chunk.get("sections") {
+            Some(sections) => (sections, "palette", "data"),
+            None => (
+                chunk
+                    .get("Level")
+                    .and_then(|l| l.get("Sections"))
+                    .ok_or_else(|| malformed("no sections"))?,
+                "Palette",
+                "BlockStates",
+            ),
+        };
+        let sections = match sections {
+            Tag::List(sections) => sections,
+            _ => return Err(malformed("sections isn't a list")),
+        };
+
+        let mut result = HashMap::new();
+        for section in sections {
+            let y = match section.get("Y") {
+                Some(Tag::Byte(y)) => i32::from(*y),
+                _ => return Err(malformed("section has no Y")),
+            };
+            let states = section.get("block_states").unwrap_or(section);
+            let palette = match states.get(palette) {
+                Some(Tag::List(palette)) => palette
+                    .iter()
+                    .map(block)
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| malformed("invalid block in palette"))?,
+                // Sections of only lighting have no blocks
+                _ => continue,
+            };
+            let data = match states.get(data) {
+                Some(Tag::LongArray(data)) => data.clone(),
+                _ => Vec::new(),
+            };
+            result.insert(y, Section { palette, data });
+        }
+        Ok(result)
+    }
+}
+
+/// A block from a palette entry
+fn block(tag: &Tag) -> Option<Block> {
+    let name = match tag.get("Name")? {
+        Tag::String(name) => name.clone(),
+        _ => return None,
+    };
+    let properties = match tag.get("Properties") {
+        Some(Tag::Compound(properties)) => properties
+            .iter()
+            .map(|(k, v)| match v {
+                Tag::String(v) => Some((k.clone(), v.clone())),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+        _ => Default::default(),
+    };
+    Some(Block { name, properties })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{Layout, ReadError};
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use std::io::Write;
+
+    /// The memory written into the chunks below
+    const MEMORY: [u8; 2] = [0xa5, 0x0f];
+
+    fn compound(tags: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(
+            tags.into_iter()
+                .map(|(name, tag)| (name.to_string(), tag))
+                .collect(),
+        )
+    }
+
+    /// Air, then the redstone block of a set bit
+    fn palette() -> Tag {
+        let block = |name: &str| compound(vec![("Name", Tag::String(name.to_string()))]);
+        Tag::List(vec![
+            block("minecraft:air"),
+            block("minecraft:redstone_block"),
+        ])
+    }
+
+    /// Bit `x` of byte `z` of `MEMORY` as block states, with 16 4-bit states to a long
+    fn states() -> Tag {
+        Tag::LongArray(
+            MEMORY
+                .iter()
+                .map(|byte| {
+                    (0..8)
+                        .filter(|bit| byte >> bit & 1 == 1)
+                        .map(|bit| 1 << (bit * 4))
+                        .sum()
+                })
+                .collect(),
+        )
+    }
+
+    /// A region with one chunk, at chunk 0 0
+    fn region(compression: u8, chunk: &[u8]) -> Region {
+        let mut data = vec![0; 8192];
+        // The chunk starts at sector 2 and is one sector long
+        data[..4].copy_from_slice(&[0, 0, 2, 1]);
+        data.extend(u32::try_from(chunk.len() + 1).unwrap().to_be_bytes());
+        data.push(compression);
+        data.extend(chunk);
+        data.resize(data.len().max(3 * 4096), 0);
+        Region::new(0, 0, data)
+    }
+
+    fn nbt(chunk: &Tag) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        chunk.write_root("", &mut bytes).unwrap();
+        bytes
+    }
+
+    fn read_memory(region: &mut Region) -> Result<Vec<u8>, ReadError> {
+        let layout: Layout = serde_json::from_str(
+            r#"{ "origin": [0, 0, 0], "address_step": [0, 0, 1], "bit_step": [1, 0, 0] }"#,
+        )
+        .unwrap();
+        layout.read_memory(region, MEMORY.len())
+    }
+
+    #[test]
+    fn reads_level_sections() {
+        let chunk = compound(vec![
+            ("DataVersion", Tag::Int(2586)),
+            (
+                "Level",
+                compound(vec![(
+                    "Sections",
+                    Tag::List(vec![
+                        // Sections of only lighting have no palette
+                        compound(vec![("Y", Tag::Byte(-1))]),
+                        compound(vec![
+                            ("Y", Tag::Byte(0)),
+                            ("Palette", palette()),
+                            ("BlockStates", states()),
+                        ]),
+                    ]),
+                )]),
+            ),
+        ]);
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&nbt(&chunk)).unwrap();
+        let mut region = region(2, &zlib.finish().unwrap());
+        assert_eq!(read_memory(&mut region).unwrap(), MEMORY);
+        assert_eq!(region.block([0, 16, 0]).unwrap().name, "minecraft:air");
+    }
+
+    #[test]
+    fn reads_block_states() {
+        let chunk = compound(vec![
+            ("DataVersion", Tag::Int(3465)),
+            (
+                "sections",
+                Tag::List(vec![compound(vec![
+                    ("Y", Tag::Byte(0)),
+                    (
+                        "block_states",
+                        compound(vec![("palette", palette()), ("data", states())]),
+                    ),
+                ])]),
+            ),
+        ]);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&nbt(&chunk)).unwrap();
+        assert_eq!(
+            read_memory(&mut region(1, &gzip.finish().unwrap())).unwrap(),
+            MEMORY
+        );
+        assert_eq!(read_memory(&mut region(3, &nbt(&chunk))).unwrap(), MEMORY);
+    }
+
+    #[test]
+    fn malformed_regions_are_errors() {
+        let block = |mut region: Region| region.block([0, 0, 0]).unwrap_err();
+
+        // A length of 0 doesn't even cover the compression byte
+        let mut empty = region(3, &[]);
+        empty.data[8192..8196].copy_from_slice(&[0; 4]);
+        assert!(matches!(block(empty), Error::Malformed(_)));
+
+        assert!(matches!(
+            block(region(4, &[0])),
+            Error::UnsupportedCompression(4)
+        ));
+        // A chunk whose sections are a list of -1 compounds
+        let mut chunk = nbt(&compound(vec![("sections", Tag::List(Vec::new()))]));
+        let len = chunk.len();
+        chunk[len - 6..len - 1].copy_from_slice(&[10, 0xff, 0xff, 0xff, 0xff]);
+        assert!(matches!(
+            block(region(3, &chunk)),
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidData
+        ));
+
+        assert!(matches!(
+            block(region(3, &nbt(&compound(vec![])))),
+            Error::Malformed(_)
+        ));
+        assert!(matches!(
+            block(Region::new(0, 0, vec![0; 8192])),
+            Error::MissingChunk { x: 0, z: 0 }
+        ));
+        assert!(matches!(
+            region(3, &[]).block([512, 0, 0]),
+            Err(Error::OutsideRegion { .. })
+        ));
+        assert!(matches!(
+            Region::open("region.mca"),
+            Err(Error::UnknownPosition(_))
+        ));
+    }
+}
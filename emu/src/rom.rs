@@ -0,0 +1,156 @@
+//! Per-bit layouts of the microcode and program ROMs, for building them in Minecraft
+
+use crate::{microcode, MachineError, Microcode, Version};
+use clap::ArgEnum;
+use serde::Serialize;
+
+/// A file format for a `Rom`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, ArgEnum)]
+pub enum Format {
+    /// One row per address and one column per bit, with bits as 0 or 1
+    #[default]
+    Csv,
+    /// An object with the columns and a list of words, with bits as booleans
+    Json,
+}
+
+/// A single address of a ROM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Word {
+    pub address: usize,
+    /// Whether each column is set, in the same order as `Rom::columns`
+    pub bits: Vec<bool>,
+}
+
+/// Which bits are set at each address of a ROM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rom {
+    /// The number of address lines
+    pub address_bits: u32,
+    /// The name of each bit, least significant first
+    pub columns: Vec<String>,
+    pub words: Vec<Word>,
+}
+
+impl Rom {
+    /// The microcode ROM, one column per control line
+    ///
+    /// The address is the opcode followed by the microstep, so on v2 microstep 3 of opcode
+    /// `0x7` is at `0x7 << 3 | 3`.
+    #[must_use]
+    pub fn microcode(microcode: &Microcode) -> Self {
+        let controls = microcode::control_names(microcode.version());
+        let micro_bits = microcode.steps().next_power_of_two().trailing_zeros();
+        let opcode_bits = match microcode.version() {
+            Version::V1 | Version::V2 => 4,
+            Version::V3 => 8,
+        };
+
+        let words = microcode
+            .iter()
+            .flat_map(|(opcode, steps)| {
+                steps
+                    .iter()
+                    .enumerate()
+                    .map(move |(step, bits)| (opcode, step, *bits))
+            })
+            .map(|(opcode, step, bits)| Word {
+                address: usize::from(opcode) << micro_bits | step,
+                bits: controls.iter().map(|(_, bit)| bits & bit != 0).collect(),
+            })
+            .collect();
+
+        Self {
+            address_bits: opcode_bits + micro_bits,
+            columns: controls.into_iter().map(|(name, _)| name).collect(),
+            words,
+        }
+    }
+
+    /// The program ROM holding a memory image, one column per bit of each byte
+    ///
+    /// The image is padded with zeros to the size of the version's memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the image is larger than the version's memory.
+    pub fn program(version: Version, image: &[u8]) -> Result<Self, MachineError> {
+        let size = version.memory_size();
+        if image.len() > size {
+            return Err(MachineError::ProgramTooLarge {
+                len: image.len(),
+                max: size,
+            });
+        }
+        let words = (0..size)
+            .map(|address| {
+                let byte = image.get(address).copied().unwrap_or_default();
+                Word {
+                    address,
+                    bits: (0..8).map(|i| byte & 1 << i != 0).collect(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            address_bits: size.next_power_of_two().trailing_zeros(),
+            columns: (0..8).map(|i| format!("BIT_{}", i)).collect(),
+            words,
+        })
+    }
+
+    /// The ROM as CSV, with a header row of `address` and the column names
+    #[must_use]
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("address,{}\n", self.columns.join(","));
+        for word in &self.words {
+            csv.push_str(&word.address.to_string());
+            for bit in &word.bits {
+                csv.push_str(if *bit { ",1" } else { ",0" });
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// The ROM as pretty-printed JSON
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a Rom is always valid JSON")
+    }
+
+    /// The ROM in the given format
+    #[must_use]
+    pub fn to_format(&self, format: Format) -> String {
+        match format {
+            Format::Csv => self.to_csv(),
+            Format::Json => self.to_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_is_padded_to_memory() {
+        let rom = Rom::program(Version::V2, &[0x81]).unwrap();
+        assert_eq!(rom.address_bits, 4);
+        assert_eq!(rom.words.len(), 16);
+        assert_eq!(
+            rom.words[0].bits,
+            [true, false, false, false, false, false, false, true]
+        );
+        assert!(rom.words[15].bits.iter().all(|bit| !bit));
+    }
+
+    #[test]
+    fn program_must_fit_in_memory() {
+        assert_eq!(
+            Rom::program(Version::V1, &[0; 17]),
+            Err(MachineError::ProgramTooLarge { len: 17, max: 16 })
+        );
+        assert!(Rom::program(Version::V3, &[0; 256]).is_ok());
+    }
+}
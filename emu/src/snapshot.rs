@@ -0,0 +1,229 @@
+//! Saving and restoring the full state of a machine
+
+use crate::{v1, v2, v3, Machine};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The state of a machine of any version, tagged with its version when serialized
+///
+/// Deserializing checks the machine is in a state it could have reached, so it can be stepped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "version", rename_all = "lowercase", try_from = "Unchecked")]
+#[allow(clippy::large_enum_variant)]
+pub enum Snapshot {
+    V1(v1::PuttPc),
+    V2(v2::PuttPc),
+    V3(v3::PuttPc),
+}
+
+/// A snapshot as it was read, before it's checked
+#[derive(Deserialize)]
+#[serde(tag = "version", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)]
+enum Unchecked {
+    V1(v1::PuttPc),
+    V2(v2::PuttPc),
+    V3(v3::PuttPc),
+}
+
+impl TryFrom<Unchecked> for Snapshot {
+    type Error = String;
+
+    fn try_from(snapshot: Unchecked) -> Result<Self, Self::Error> {
+        fn check(machine: &impl Machine) -> Result<(), String> {
+            let steps = machine.microcode().steps();
+            if machine.micro() >= steps {
+                return Err(format!(
+                    "micro {} is past the {} microsteps of each instruction",
+                    machine.micro(),
+                    steps
+                ));
+            }
+            Ok(())
+        }
+
+        Ok(match snapshot {
+            Unchecked::V1(p) => check(&p).map(|()| Self::V1(p))?,
+            Unchecked::V2(p) => check(&p).map(|()| Self::V2(p))?,
+            Unchecked::V3(p) => check(&p).map(|()| Self::V3(p))?,
+        })
+    }
+}
+
+impl From<v1::PuttPc> for Snapshot {
+    fn from(p: v1::PuttPc) -> Self {
+        Self::V1(p)
+    }
+}
+
+impl From<v2::PuttPc> for Snapshot {
+    fn from(p: v2::PuttPc) -> Self {
+        Self::V2(p)
+    }
+}
+
+impl From<v3::PuttPc> for Snapshot {
+    fn from(p: v3::PuttPc) -> Self {
+        Self::V3(p)
+    }
+}
+
+/// Implement `Serialize` and `Deserialize` for a `bitflags` type as its raw bits
+macro_rules! serde_bits {
+    ($t:ty) => {
+        impl serde::Serialize for $t {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                self.bits().serialize(s)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $t {
+            fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                let bits = u32::deserialize(d)?;
+                Self::from_bits(bits).ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "invalid bits {:#b} for {}",
+                        bits,
+                        stringify!($t)
+                    ))
+                })
+            }
+        }
+    };
+}
+pub(crate) use serde_bits;
+
+/// (De)serialize a machine's microcode as its text, or as nothing if it's the built-in microcode
+///
+/// The version comes from the machine, so `deserialize` needs wrapping by each machine.
+pub(crate) mod custom_microcode {
+    use crate::{Microcode, Version};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::sync::Arc;
+
+    pub fn serialize<S: Serializer>(microcode: &Arc<Microcode>, s: S) -> Result<S::Ok, S::Error> {
+        if **microcode == Microcode::builtin(microcode.version()) {
+            s.serialize_none()
+        } else {
+            s.serialize_some(&microcode.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        version: Version,
+        d: D,
+    ) -> Result<Arc<Microcode>, D::Error> {
+        let microcode = match Option::<String>::deserialize(d)? {
+            Some(text) => Microcode::parse(version, &text).map_err(D::Error::custom)?,
+            None => Microcode::builtin(version),
+        };
+        Ok(Arc::new(microcode))
+    }
+}
+
+/// (De)serialize a byte array of any length, as serde only supports up to 32 elements
+pub(crate) mod array {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: Serializer, const N: usize>(a: &[u8; N], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(a)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        d: D,
+    ) -> Result<[u8; N], D::Error> {
+        let v = Vec::<u8>::deserialize(d)?;
+        let len = v.len();
+        v.try_into()
+            .map_err(|_| D::Error::invalid_length(len, &format!("{} bytes", N).as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, Microcode, Version};
+
+    /// Run a snapshot to the end
+    fn resume(snapshot: Snapshot) -> Vec<u8> {
+        match snapshot {
+            Snapshot::V1(mut p) => p.run(),
+            Snapshot::V2(mut p) => p.run(),
+            Snapshot::V3(mut p) => p.run(),
+        }
+        .unwrap()
+    }
+
+    /// Check that saving after `steps` steps, loading and continuing outputs the same as running
+    /// uninterrupted
+    fn round_trip<M: Machine<Output = u8> + Clone + Into<Snapshot>>(mut machine: M, steps: usize) {
+        let expected = machine.clone().run().unwrap();
+        let mut output = Vec::new();
+        for _ in 0..steps {
+            output.extend(machine.step().unwrap());
+        }
+        let snapshot: Snapshot = machine.into();
+        let loaded: Snapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert_eq!(loaded, snapshot);
+        output.extend(resume(loaded));
+        assert_eq!(output, expected);
+    }
+
+    fn program(version: Version, path: &str) -> Vec<u8> {
+        asm::assemble_file(version, path).unwrap()
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let image = program(Version::V1, "../asm/v1/simple_add.S");
+        round_trip(v1::PuttPc::with_input(&image).unwrap(), 7);
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let image = program(Version::V2, "../asm/v2/fibonacci.S");
+        round_trip(v2::PuttPc::with_input(&image).unwrap(), 53);
+    }
+
+    #[test]
+    fn v3_round_trip() {
+        let image = program(Version::V3, "../asm/v3/test_all_out.S");
+        round_trip(v3::PuttPc::with_input(&image).unwrap(), 1001);
+    }
+
+    #[test]
+    fn custom_microcode_and_check_bus_are_saved() {
+        // `out` shows B instead of A
+        let text = Microcode::builtin(Version::V2)
+            .to_string()
+            .replace("0x0e 2: OUTPUT_IN | A_OUT", "0x0e 2: OUTPUT_IN | B_OUT");
+        let microcode = Microcode::parse(Version::V2, &text).unwrap();
+        assert_ne!(microcode, Microcode::builtin(Version::V2));
+
+        let image = asm::assemble(Version::V2, "ldav 1\ntxb\nldav 2\nout\nhlt").unwrap();
+        let mut machine = v2::PuttPc::with_input(&image).unwrap();
+        machine.set_microcode(microcode.clone()).unwrap();
+        machine.set_check_bus(true);
+        assert_eq!(machine.clone().run().unwrap(), [1]);
+        round_trip(machine.clone(), 9);
+
+        let json = serde_json::to_string(&Snapshot::from(machine)).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            Snapshot::V2(loaded) => {
+                assert_eq!(*loaded.microcode, microcode);
+                assert!(loaded.check_bus);
+            }
+            snapshot => panic!("loaded {:?}", snapshot),
+        }
+    }
+
+    #[test]
+    fn micro_past_the_microcode_is_rejected() {
+        let mut json = serde_json::to_value(Snapshot::from(v3::PuttPc::new())).unwrap();
+        json["micro"] = usize::MAX.into();
+        let e = serde_json::from_value::<Snapshot>(json).unwrap_err();
+        assert!(e.to_string().contains("micro"), "{}", e);
+    }
+}
@@ -0,0 +1,344 @@
+//! Labels and source lines from an assembler, for showing addresses the way the program wrote them
+//!
+//! Both files customasm can write alongside an image are understood: symbol files of
+//! `name = 0x...` lines, and annotated listings with an `outp | addr | data ; source` row for
+//! everything emitted. The same files are written by `asm::assemble_file_with_symbols`.
+
+// Only `std` is used, so the build script can include this along with `asm`
+use crate::located::Located;
+use std::{collections::BTreeMap, error, fmt, fs, io, path::Path};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    /// A line is neither a symbol nor a listing row
+    BadLine(String),
+    NotAnAddress(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "could not read symbols"),
+            Self::BadLine(s) => write!(f, "`{}` isn't a symbol or listing line", s),
+            Self::NotAnAddress(s) => write!(f, "`{}` isn't an address", s),
+        }
+    }
+}
+
+impl error::Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub type Error = Located<ErrorKind>;
+
+/// The source line that emitted some bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Source {
+    len: usize,
+    text: String,
+}
+
+/// The labels and source lines of a program, by address
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Symbols {
+    /// Every label, in the order they were defined at each address
+    labels: BTreeMap<usize, Vec<String>>,
+    sources: BTreeMap<usize, Source>,
+}
+
+impl Symbols {
+    /// Read a symbol file or listing
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or has a line that isn't a symbol or listing row.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).map_err(|e| Error::new(0, ErrorKind::Io(e)).in_file(path))?;
+        Self::parse(&text).map_err(|e| e.in_file(path))
+    }
+
+    /// Read symbols or a listing from text
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a line isn't a symbol or listing row.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut symbols = Self::default();
+        // The last non-local label in a listing, used to qualify local `.labels`
+        let mut scope = String::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            let columns: Vec<_> = line.splitn(3, '|').collect();
+            let (address, rest) = match (columns.as_slice(), trimmed.split_once('=')) {
+                ([_, address, rest], _) => (address.trim(), rest),
+                (_, Some((name, address))) => {
+                    let address = parse_address(address.trim()).ok_or_else(|| {
+                        Error::new(line_number, ErrorKind::NotAnAddress(address.trim().into()))
+                    })?;
+                    symbols.add_label(name.trim(), address);
+                    continue;
+                }
+                _ => return Err(Error::new(line_number, ErrorKind::BadLine(trimmed.into()))),
+            };
+            let address = match parse_address(address) {
+                Some(address) => address,
+                // The header row names the columns
+                None if address == "addr" => continue,
+                None => {
+                    return Err(Error::new(
+                        line_number,
+                        ErrorKind::NotAnAddress(address.into()),
+                    ))
+                }
+            };
+            let (data, text) = rest.split_once(';').unwrap_or((rest, ""));
+            let text = text.trim();
+            let len = data.chars().filter(char::is_ascii_hexdigit).count() / 2;
+
+            if len > 0 {
+                symbols.add_source(address, len, text);
+            } else if let Some(name) = text.strip_suffix(':') {
+                if !name.starts_with('.') {
+                    scope = name.to_string();
+                    symbols.add_label(name, address);
+                } else {
+                    symbols.add_label(&format!("{}{}", scope, name), address);
+                }
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    /// Add the symbols of `other`, as if both files had been loaded
+    pub fn extend(&mut self, other: Self) {
+        for (address, names) in other.labels {
+            for name in names {
+                self.add_label(&name, address);
+            }
+        }
+        self.sources.extend(other.sources);
+    }
+
+    /// Name `address`, unless it already has this name
+    pub fn add_label(&mut self, name: &str, address: usize) {
+        let names = self.labels.entry(address).or_default();
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+
+    /// Note that the `len` bytes at `address` were emitted by the source line `text`
+    pub fn add_source(&mut self, address: usize, len: usize, text: &str) {
+        self.sources.insert(
+            address,
+            Source {
+                len,
+                text: text.to_string(),
+            },
+        );
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.sources.is_empty()
+    }
+
+    /// The labels at exactly `address`
+    #[must_use]
+    pub fn labels_at(&self, address: usize) -> &[String] {
+        self.labels.get(&address).map_or(&[], Vec::as_slice)
+    }
+
+    /// The address of a label
+    #[must_use]
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(_, names)| names.iter().any(|n| n == name))
+            .map(|(address, _)| *address)
+    }
+
+    /// `address` relative to the closest label at or before it, like `loop` or `data+1`
+    #[must_use]
+    pub fn describe(&self, address: usize) -> Option<String> {
+        let (label_address, names) = self.labels.range(..=address).next_back()?;
+        let name = names.first()?;
+        Some(match address - label_address {
+            0 => name.clone(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+
+    /// The source line that emitted the byte at `address`
+    #[must_use]
+    pub fn source_at(&self, address: usize) -> Option<&str> {
+        let (start, source) = self.sources.range(..=address).next_back()?;
+        (address < start + source.len).then_some(source.text.as_str())
+    }
+
+    /// A symbol file of every label, in the format of customasm's `symbols`
+    #[must_use]
+    pub fn symbol_file(&self) -> String {
+        let mut file = String::new();
+        for (address, names) in &self.labels {
+            for name in names {
+                file.push_str(&format!("{} = 0x{:x}\n", name, address));
+            }
+        }
+        file
+    }
+
+    /// A listing of `image` by the lines that emitted it, in the format of customasm's `annotated`
+    #[must_use]
+    pub fn listing(&self, image: &[u8]) -> String {
+        let mut listing = " outp | addr | data (base 16)\n\n".to_string();
+        let mut addresses: Vec<_> = self
+            .labels
+            .keys()
+            .chain(self.sources.keys())
+            .copied()
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        for address in addresses {
+            for name in self.labels_at(address) {
+                // Local labels were qualified with their scope, so only the last part was written
+                let name = name.rfind('.').map_or(name.as_str(), |i| &name[i..]);
+                listing.push_str(&format!(
+                    "{:>3x}:0 | {:>4x} | {:<8} ; {}:\n",
+                    address, address, "", name
+                ));
+            }
+            if let Some(source) = self.sources.get(&address) {
+                let end = (address + source.len).min(image.len());
+                let data: String = image
+                    .get(address..end)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                listing.push_str(&format!(
+                    "{:>3x}:0 | {:>4x} | {:<8} ; {}\n",
+                    address, address, data, source.text
+                ));
+            }
+        }
+        listing
+    }
+}
+
+fn parse_address(s: &str) -> Option<usize> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    usize::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, Version};
+
+    /// customasm's `annotated` output for `asm/v2/fibonacci.S`
+    const FIBONACCI_LISTING: &str = " outp | addr | data (base 16)
+
+  0:0 |    0 |          ; loop:
+  0:0 |    0 | 2a       ; ldam data
+  1:0 |    1 | 40       ; txb
+  2:0 |    2 | 2b       ; ldam (data+1)
+  3:0 |    3 | 3a       ; sta data
+  4:0 |    4 | e0       ; out
+  5:0 |    5 | 50       ; add
+  6:0 |    6 | 3b       ; sta (data+1)
+  7:0 |    7 | d9       ; jc end
+  8:0 |    8 | b0       ; jmp loop
+  9:0 |    9 |          ; end:
+  9:0 |    9 | f0       ; hlt
+  a:0 |    a |          ; data:
+  a:0 |    a | 00 01    ; #d 0`8, 1`8
+";
+
+    fn scoped() -> Symbols {
+        let mut symbols = Symbols::default();
+        symbols.add_label("start", 0);
+        symbols.add_label("start.inner", 2);
+        symbols.add_label("other", 4);
+        symbols.add_label("other.inner", 4);
+        symbols.add_source(0, 2, "mov %a 0");
+        symbols.add_source(2, 1, "out");
+        symbols.add_source(3, 1, "hlt");
+        symbols.add_source(4, 2, "#d 0x1234");
+        symbols
+    }
+
+    #[test]
+    fn parses_customasm_listing() {
+        let symbols = Symbols::parse(FIBONACCI_LISTING).unwrap();
+        assert_eq!(symbols.labels_at(0), ["loop"]);
+        assert_eq!(symbols.address_of("end"), Some(9));
+        assert_eq!(symbols.address_of("data"), Some(10));
+        assert_eq!(symbols.describe(11).as_deref(), Some("data+1"));
+        assert_eq!(symbols.source_at(2), Some("ldam (data+1)"));
+        assert_eq!(symbols.source_at(11), Some("#d 0`8, 1`8"));
+        assert_eq!(symbols.source_at(12), None);
+    }
+
+    #[test]
+    fn listing_matches_the_assembler() {
+        let (image, symbols) =
+            asm::assemble_file_with_symbols(Version::V2, "../asm/v2/fibonacci.S").unwrap();
+        assert_eq!(Symbols::parse(FIBONACCI_LISTING).unwrap(), symbols);
+        assert_eq!(Symbols::parse(&symbols.listing(&image)).unwrap(), symbols);
+    }
+
+    #[test]
+    fn listing_round_trips() {
+        let symbols = scoped();
+        let listing = symbols.listing(&[0x02, 0x00, 0xe0, 0xff, 0x12, 0x34]);
+        assert!(listing.contains("; .inner:"));
+        assert_eq!(Symbols::parse(&listing).unwrap(), symbols);
+    }
+
+    #[test]
+    fn symbol_file_round_trips() {
+        let mut symbols = scoped();
+        symbols.sources.clear();
+        assert_eq!(
+            symbols.symbol_file(),
+            "start = 0x0\nstart.inner = 0x2\nother = 0x4\nother.inner = 0x4\n"
+        );
+        assert_eq!(Symbols::parse(&symbols.symbol_file()).unwrap(), symbols);
+    }
+
+    #[test]
+    fn errors() {
+        let e = Symbols::parse("a = 0x1\nnonsense").unwrap_err();
+        assert_eq!(e.line, 2);
+        assert!(matches!(e.kind, ErrorKind::BadLine(l) if l == "nonsense"));
+        assert!(matches!(
+            Symbols::parse("a = zz").unwrap_err().kind,
+            ErrorKind::NotAnAddress(a) if a == "zz"
+        ));
+        assert!(matches!(
+            Symbols::parse("  0:0 |   zz | 2a ; out").unwrap_err().kind,
+            ErrorKind::NotAnAddress(a) if a == "zz"
+        ));
+    }
+}
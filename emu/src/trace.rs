@@ -0,0 +1,227 @@
+//! Machine-readable traces of a `Machine`, one event per microstep or instruction
+
+use crate::{disasm, flag_names, register_name, Machine, Register, Symbols};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A byte of memory written during an event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryWrite {
+    pub address: usize,
+    pub value: u8,
+}
+
+/// What a microstep or instruction did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// The number of steps run, including this one
+    pub step: u64,
+    /// The address of the instruction being run
+    pub pc: usize,
+    /// The microstep that ran, or the first one of an instruction
+    pub micro: usize,
+    /// The instruction, disassembled
+    pub instruction: String,
+    /// Where the instruction is relative to a label, if symbols were given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// The source line of the instruction, if a listing was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The registers afterwards, by name
+    pub registers: BTreeMap<String, u8>,
+    /// The flags set afterwards
+    pub flags: Vec<String>,
+    pub memory_writes: Vec<MemoryWrite>,
+    pub output: Vec<u8>,
+}
+
+/// Builds `Event`s from the steps of a machine
+pub struct Tracer {
+    instructions: bool,
+    symbols: Symbols,
+    steps: u64,
+    /// The address and disassembly of the instruction being run
+    current: Option<(usize, String)>,
+    /// The event being built, with the memory from before it
+    event: Option<(Event, Vec<u8>)>,
+}
+
+impl Tracer {
+    /// Trace every microstep, or only whole instructions if `instructions` is set
+    #[must_use]
+    pub fn new(instructions: bool) -> Self {
+        Self {
+            instructions,
+            symbols: Symbols::default(),
+            steps: 0,
+            current: None,
+            event: None,
+        }
+    }
+
+    /// Name instructions and their operands after the labels and source lines in `symbols`
+    #[must_use]
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Note the state before a step, which must be called before every step
+    pub fn before(&mut self, machine: &impl Machine) {
+        // The counter only points at the instruction before it's fetched
+        if machine.micro() == 0 || self.current.is_none() {
+            let pc = usize::from(machine.regs()[Register::Counter as usize])
+                .min(machine.memory().len() - 1);
+            let line = disasm::disassemble_at_with_symbols(
+                machine.version(),
+                machine.memory(),
+                pc,
+                &self.symbols,
+            );
+            self.current = Some((pc, line.text));
+        }
+
+        if self.event.is_none() {
+            let (pc, instruction) = self.current.clone().expect("set above");
+            let event = Event {
+                step: 0,
+                pc,
+                micro: machine.micro(),
+                instruction,
+                label: self.symbols.describe(pc),
+                source: self.symbols.source_at(pc).map(str::to_string),
+                registers: BTreeMap::new(),
+                flags: Vec::new(),
+                memory_writes: Vec::new(),
+                output: Vec::new(),
+            };
+            self.event = Some((event, machine.memory().to_vec()));
+        }
+    }
+
+    /// Note the state after a step and its output, returning an event if it finished one
+    ///
+    /// # Panics
+    ///
+    /// Panics if `before` wasn't called before the step.
+    pub fn after<M>(&mut self, machine: &M, output: Option<u8>) -> Option<Event>
+    where
+        M: Machine<Output = u8>,
+    {
+        let (mut event, memory) = self
+            .event
+            .take()
+            .expect("before is called before each step");
+        self.steps += 1;
+        event.output.extend(output);
+
+        if self.instructions && machine.micro() != 0 && !machine.is_halted() {
+            self.event = Some((event, memory));
+            return None;
+        }
+
+        event.step = self.steps;
+        event.registers = machine
+            .regs()
+            .iter()
+            .enumerate()
+            .map(|(r, v)| (register_name(r), *v))
+            .collect();
+        event.flags = flag_names()
+            .into_iter()
+            .filter(|(_, bit)| machine.flags() & bit != 0)
+            .map(|(name, _)| name)
+            .collect();
+        event.memory_writes = memory
+            .iter()
+            .zip(machine.memory())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(address, (_, value))| MemoryWrite {
+                address,
+                value: *value,
+            })
+            .collect();
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PuttPc;
+    use serde_json::{json, Value};
+
+    /// The lines of JSON traced from running `input` to its halt
+    fn trace(input: &[u8], instructions: bool) -> Vec<String> {
+        let mut machine = PuttPc::with_input(input).unwrap();
+        let mut tracer = Tracer::new(instructions);
+        let mut lines = Vec::new();
+        while !machine.is_halted() {
+            tracer.before(&machine);
+            let output = machine.step().unwrap();
+            lines.extend(
+                tracer
+                    .after(&machine, output)
+                    .map(|event| serde_json::to_string(&event).unwrap()),
+            );
+        }
+        lines
+    }
+
+    #[test]
+    fn instructions() {
+        // ldav 5; out; hlt
+        let lines = trace(&[0x15, 0xe0, 0xf0], true);
+        let events: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+
+        let out = &events[1];
+        assert_eq!(out["step"], 6);
+        assert_eq!(out["pc"], 1);
+        assert_eq!(out["micro"], 0);
+        assert_eq!(out["instruction"], "out");
+        assert_eq!(
+            out["registers"],
+            json!({
+                "A": 5,
+                "B": 0,
+                "Counter": 2,
+                "Instruction": 0xe0,
+                "Output": 5,
+                "RamAddress": 1,
+            })
+        );
+        assert_eq!(out["output"], json!([5]));
+        assert_eq!(events[2]["instruction"], "hlt");
+        assert_eq!(events[2]["output"], json!([]));
+    }
+
+    #[test]
+    fn microsteps() {
+        // ldav 5; sta 15; hlt
+        let lines = trace(&[0x15, 0x3f, 0xf0], false);
+        let events: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let steps: Vec<_> = events.iter().map(|e| e["step"].as_u64().unwrap()).collect();
+        assert_eq!(steps, (1..=events.len() as u64).collect::<Vec<_>>());
+
+        let writes: Vec<_> = events
+            .iter()
+            .filter(|e| e["memory_writes"] != json!([]))
+            .collect();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0]["instruction"], "sta 0xf");
+        assert_eq!(
+            writes[0]["memory_writes"],
+            json!([{ "address": 15, "value": 5 }])
+        );
+        assert!(writes[0].get("label").is_none());
+    }
+}
@@ -0,0 +1,438 @@
+//! A full-screen terminal interface for running any `Machine`
+
+use puttpc_emu::{disasm, flag_names, register_name, Machine, Register, Reset, Symbols};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+const HELP: &str = "space run/pause  s step  n next  +/- speed  r reset  R power-on reset  q quit";
+
+/// How long to wait for a key before drawing the next frame
+const FRAME: Duration = Duration::from_millis(33);
+
+/// The fastest the machine can be run, in steps per second
+const MAX_SPEED: u32 = 1 << 20;
+
+const LIT: Style = Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+const DIM: Style = Style::new().fg(Color::DarkGray);
+const COUNTER: Style = Style::new().fg(Color::Black).bg(Color::Yellow);
+const RAM_ADDRESS: Style = Style::new().fg(Color::Black).bg(Color::Cyan);
+const BOTH: Style = Style::new().fg(Color::Black).bg(Color::Green);
+
+struct Tui<M> {
+    machine: M,
+    symbols: Symbols,
+    /// The name and bit of every control, in the order they're shown
+    controls: Vec<(String, u32)>,
+    running: bool,
+    /// Steps per second while running
+    speed: u32,
+    /// Steps owed to the machine since the last frame, so slow speeds still make progress
+    owed: f64,
+    steps: u64,
+    outputs: Vec<u8>,
+    /// Why the machine last stopped, shown in the status line
+    status: String,
+}
+
+/// Run the interface on `machine` until the user quits
+///
+/// # Errors
+///
+/// Returns an error if the terminal can't be drawn to or read from.
+pub fn tui<M: Machine<Output = u8>>(machine: M, symbols: Symbols) -> Result<(), Box<dyn Error>> {
+    let tui = Tui {
+        controls: machine.microcode().control_names(),
+        machine,
+        symbols,
+        running: false,
+        speed: 8,
+        owed: 0.0,
+        steps: 0,
+        outputs: Vec::new(),
+        status: String::new(),
+    };
+    let mut terminal = ratatui::init();
+    let result = tui.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<M: Machine<Output = u8>> Tui<M> {
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn Error>> {
+        let mut last = Instant::now();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(FRAME)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && self.key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            if self.running {
+                self.owed += f64::from(self.speed) * (now - last).as_secs_f64();
+                while self.running && self.owed >= 1.0 {
+                    self.owed -= 1.0;
+                    self.step();
+                }
+            }
+            last = now;
+        }
+    }
+
+    /// Handle a key press, returning whether to quit
+    fn key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char(' ') => {
+                self.running = !self.running && !self.machine.is_halted();
+                self.owed = 0.0;
+                self.status.clear();
+            }
+            KeyCode::Char('s') => {
+                self.running = false;
+                self.step();
+            }
+            KeyCode::Char('n') => {
+                self.running = false;
+                self.step();
+                while self.machine.micro() != 0 && !self.machine.is_halted() {
+                    self.step();
+                }
+            }
+            KeyCode::Char('+' | '=') => self.speed = (self.speed * 2).min(MAX_SPEED),
+            KeyCode::Char('-') => self.speed = (self.speed / 2).max(1),
+            KeyCode::Char(c @ ('r' | 'R')) => {
+                self.running = false;
+                self.machine.reset(if c == 'R' {
+                    Reset::PowerOn
+                } else {
+                    Reset::Soft
+                });
+                self.status = "Reset".to_string();
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn step(&mut self) {
+        if self.machine.is_halted() {
+            self.running = false;
+            self.status = "Halted".to_string();
+            return;
+        }
+        self.steps += 1;
+        match self.machine.step() {
+            Ok(out) => self.outputs.extend(out),
+            Err(e) => {
+                self.running = false;
+                self.status = e.to_string();
+            }
+        }
+        if self.machine.is_halted() && self.status.is_empty() {
+            self.status = "Halted".to_string();
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame<'_>) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, middle, right] = Layout::horizontal([
+            Constraint::Length(32),
+            Constraint::Min(54),
+            Constraint::Length(16),
+        ])
+        .areas(main);
+
+        let flags = flag_names();
+        let [registers, flags_area, controls] = Layout::vertical([
+            Constraint::Length(self.machine.regs().len() as u16 + 2),
+            Constraint::Length(flags.len() as u16 + 2),
+            Constraint::Min(0),
+        ])
+        .areas(left);
+        let rows = self.machine.memory().len().div_ceil(16);
+        let [memory, program] =
+            Layout::vertical([Constraint::Length(rows as u16 + 3), Constraint::Min(0)])
+                .areas(middle);
+
+        self.draw_registers(frame, registers);
+        self.draw_flags(frame, flags_area, &flags);
+        self.draw_controls(frame, controls);
+        self.draw_memory(frame, memory);
+        self.draw_program(frame, program);
+        self.draw_output(frame, right);
+
+        let state = if self.running {
+            format!("running at {} steps/s", self.speed)
+        } else {
+            format!("paused ({} steps/s)", self.speed)
+        };
+        let line = Line::from(vec![
+            Span::styled(format!(" {} | step {} ", state, self.steps), LIT),
+            Span::raw(if self.status.is_empty() {
+                HELP
+            } else {
+                &self.status
+            }),
+        ]);
+        frame.render_widget(Paragraph::new(line), status);
+    }
+
+    fn draw_registers(&self, frame: &mut Frame<'_>, area: Rect) {
+        frame.render_widget(
+            Paragraph::new(register_lines(&self.machine))
+                .block(Block::bordered().title("Registers")),
+            area,
+        );
+    }
+
+    fn draw_flags(&self, frame: &mut Frame<'_>, area: Rect, flags: &[(String, u32)]) {
+        frame.render_widget(
+            Paragraph::new(flag_lines(&self.machine, flags))
+                .block(Block::bordered().title("Flags")),
+            area,
+        );
+    }
+
+    fn draw_controls(&self, frame: &mut Frame<'_>, area: Rect) {
+        let bus = self
+            .machine
+            .bus()
+            .map_or_else(|| "--".to_string(), |b| format!("0x{:02x}", b));
+        let title = format!("Controls (micro {}, bus {})", self.machine.micro(), bus);
+        frame.render_widget(
+            Paragraph::new(control_lines(&self.machine, &self.controls))
+                .block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame<'_>, area: Rect) {
+        let title = Line::from(vec![
+            Span::raw("Memory ("),
+            Span::styled("Counter", COUNTER),
+            Span::raw(" "),
+            Span::styled("RamAddress", RAM_ADDRESS),
+            Span::raw(")"),
+        ]);
+        frame.render_widget(
+            Paragraph::new(memory_lines(&self.machine)).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_program(&self, frame: &mut Frame<'_>, area: Rect) {
+        let count = usize::from(area.height.saturating_sub(2)).max(1);
+        let title = match self
+            .symbols
+            .describe(counter(&self.machine).min(self.machine.memory().len() - 1))
+        {
+            Some(label) => format!("Program ({})", label),
+            None => "Program".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(program_lines(&self.machine, &self.symbols, count))
+                .block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_output(&self, frame: &mut Frame<'_>, area: Rect) {
+        let count = usize::from(area.height.saturating_sub(2));
+        let lines: Vec<_> = self.outputs[self.outputs.len().saturating_sub(count)..]
+            .iter()
+            .map(|out| Line::raw(format!("0x{:02x} {:3}", out, out)))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Output")),
+            area,
+        );
+    }
+}
+
+fn counter(machine: &impl Machine) -> usize {
+    usize::from(machine.regs()[Register::Counter as usize])
+}
+
+/// Each register's name and value in hex, decimal and binary
+fn register_lines(machine: &impl Machine) -> Vec<Line<'static>> {
+    machine
+        .regs()
+        .iter()
+        .enumerate()
+        .map(|(r, value)| {
+            Line::from(format!(
+                "{:<12} 0x{:02x} {:3} {:08b}",
+                register_name(r),
+                value,
+                value,
+                value
+            ))
+        })
+        .collect()
+}
+
+/// Each of `flags`, lit if it's set
+fn flag_lines(machine: &impl Machine, flags: &[(String, u32)]) -> Vec<Line<'static>> {
+    let set = machine.flags();
+    flags
+        .iter()
+        .map(|(name, bit)| {
+            if set & bit != 0 {
+                Line::styled(format!("● {}", name), LIT)
+            } else {
+                Line::styled(format!("○ {}", name), DIM)
+            }
+        })
+        .collect()
+}
+
+/// Each of `controls`, lit if it's asserted for the next step
+fn control_lines<'a>(machine: &impl Machine, controls: &'a [(String, u32)]) -> Vec<Line<'a>> {
+    let set = machine.controls();
+    controls
+        .iter()
+        .map(|(name, bit)| Line::styled(name.as_str(), if set & bit != 0 { LIT } else { DIM }))
+        .collect()
+}
+
+/// A header of columns, then memory in rows of 16 bytes with the counter and RAM address highlighted
+fn memory_lines(machine: &impl Machine) -> Vec<Line<'static>> {
+    let counter = counter(machine);
+    let ram_address = usize::from(machine.regs()[Register::RamAddress as usize]);
+
+    let mut lines = vec![Line::styled(
+        format!(
+            "    {}",
+            (0..16).map(|c| format!(" {:x} ", c)).collect::<String>()
+        ),
+        DIM,
+    )];
+    for (row, bytes) in machine.memory().chunks(16).enumerate() {
+        let mut spans = vec![Span::styled(format!("{:02x}: ", row * 16), DIM)];
+        for (col, byte) in bytes.iter().enumerate() {
+            let address = row * 16 + col;
+            let style = match (address == counter, address == ram_address) {
+                (true, true) => BOTH,
+                (true, false) => COUNTER,
+                (false, true) => RAM_ADDRESS,
+                (false, false) => Style::new(),
+            };
+            spans.push(Span::styled(format!("{:02x}", byte), style));
+            spans.push(Span::raw(" "));
+        }
+        lines.push(Line::from(spans));
+    }
+    lines
+}
+
+/// Up to `count` lines of disassembly and labels around the counter, with its line marked
+fn program_lines(machine: &impl Machine, symbols: &Symbols, count: usize) -> Vec<Line<'static>> {
+    let version = machine.version();
+    let memory = machine.memory();
+    let counter = counter(machine).min(memory.len() - 1);
+
+    // Decoding from the start keeps the lines before the counter aligned with real instructions
+    let before: Vec<_> = disasm::disassemble_with_symbols(version, &memory[..counter], symbols)
+        .into_iter()
+        .filter(|l| l.address + l.bytes.len() <= counter)
+        .collect();
+    let before = &before[before.len().saturating_sub(count / 2)..];
+
+    let mut address = counter;
+    let mut after = Vec::new();
+    while address < memory.len() && after.len() < count - before.len() {
+        let line = disasm::disassemble_at_with_symbols(version, memory, address, symbols);
+        address += line.bytes.len();
+        after.push(line);
+    }
+
+    let mut lines = Vec::new();
+    // The index of the counter's line, to keep it in view
+    let mut current = 0;
+    for line in before.iter().chain(&after) {
+        for label in symbols.labels_at(line.address) {
+            lines.push(Line::styled(format!("{}:", label), DIM));
+        }
+        let text = line.with_source(symbols);
+        if line.address == counter {
+            current = lines.len();
+            lines.push(Line::styled(format!("=> {}", text), LIT));
+        } else {
+            lines.push(Line::raw(format!("   {}", text)));
+        }
+    }
+    // Labels take up lines too, so drop lines from the top if they push the counter out
+    let excess = lines.len().saturating_sub(count);
+    lines.drain(..excess.min(current));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use puttpc_emu::v3::PuttPc;
+
+    /// The style of the byte at `address` in the memory grid
+    fn style_at(lines: &[Line<'_>], address: usize) -> Style {
+        // The first line is the column header, and each row starts with its address
+        lines[1 + address / 16].spans[1 + 2 * (address % 16)].style
+    }
+
+    #[test]
+    fn memory_highlights() {
+        let mut machine = PuttPc::new();
+        machine.regs_mut()[Register::Counter as usize] = 0x02;
+        machine.regs_mut()[Register::RamAddress as usize] = 0x11;
+        let lines = memory_lines(&machine);
+        assert_eq!(lines.len(), 1 + 16);
+        assert_eq!(style_at(&lines, 0x02), COUNTER);
+        assert_eq!(style_at(&lines, 0x11), RAM_ADDRESS);
+        assert_eq!(style_at(&lines, 0x00), Style::new());
+        assert_eq!(lines[2].spans[0].content, "10: ");
+
+        machine.regs_mut()[Register::RamAddress as usize] = 0x02;
+        let lines = memory_lines(&machine);
+        assert_eq!(style_at(&lines, 0x02), BOTH);
+        assert_eq!(style_at(&lines, 0x11), Style::new());
+    }
+
+    #[test]
+    fn panels() {
+        let machine = PuttPc::new();
+        let registers = register_lines(&machine);
+        assert_eq!(registers.len(), machine.regs().len());
+        assert_eq!(
+            registers[Register::Counter as usize].to_string(),
+            "Counter      0x00   0 00000000"
+        );
+
+        // The fetch drives the counter onto the bus
+        let controls = machine.microcode().control_names();
+        let lines = control_lines(&machine, &controls);
+        let lit: Vec<_> = lines
+            .iter()
+            .filter(|l| l.style == LIT)
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(lit, ["RAM_ADDR_IN", "COUNTER_OUT"]);
+
+        let flags = flag_lines(&machine, &flag_names());
+        assert!(flags.iter().all(|l| l.style == DIM));
+    }
+}
@@ -1,15 +1,24 @@
 //TODO: flags_in should be set later, maybe?
 
-use crate::Machine;
+use crate::{
+    isa::{self, Operand},
+    microcode::{self, Microcode},
+    snapshot::{self, serde_bits},
+    Machine, MachineError, Reset, Version,
+};
 use bitflags::bitflags;
 use derive_try_from_primitive::TryFromPrimitive;
-use std::{convert::TryFrom, fmt};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{convert::TryFrom, fmt, sync::Arc};
 
 use Controls as C;
 use Flags as F;
 use Instruction as I;
 use Register as R;
 
+/// The number of microsteps in the microcode of each instruction
+pub const MICRO_STEPS: usize = 5;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Instruction {
@@ -27,6 +36,42 @@ pub enum Instruction {
     Hlt = 0xF,
 }
 
+impl Instruction {
+    /// The mnemonic and operands of the instruction, as written in `ruledef.S`
+    #[must_use]
+    pub fn syntax(self) -> (&'static str, &'static [Operand]) {
+        isa::syntax(Version::V1, self as u8).expect("every instruction has a rule")
+    }
+
+    /// The built-in microcode: the controls for each microstep of the instruction
+    #[allow(clippy::match_same_arms)]
+    #[must_use]
+    pub fn controls(self, micro: usize) -> Controls {
+        match (self, micro) {
+            (_, 0) => C::COUNTER_OUT | C::RAM_ADDR_IN,
+            (_, 1) => C::COUNTER_INCREMENT | C::RAM_OUT | C::INSTRUCTION_IN,
+            (I::Ldav, 2) => C::INSTRUCTION_OUT | C::A_IN,
+            (I::Ldam, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
+            (I::Ldam, 3) => C::RAM_OUT | C::A_IN,
+            (I::Sta, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
+            (I::Sta, 3) => C::A_OUT | C::RAM_IN,
+            (I::Txb, 2) => C::A_OUT | C::B_IN,
+            (I::Add, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
+            (I::Add, 3) => C::RAM_OUT | C::B_IN,
+            (I::Add, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN,
+            (I::Sub, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::Sub, 3) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::Sub, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::SUBTRACT,
+            (I::Jmp, 2) => C::INSTRUCTION_OUT | C::JUMP,
+            (I::Jz, 2) => C::INSTRUCTION_OUT | C::JUMP_IF_ZERO,
+            (I::Jc, 2) => C::INSTRUCTION_OUT | C::JUMP_IF_CARRY,
+            (I::Out, 2) => C::A_OUT | C::OUTPUT_IN,
+            (I::Hlt, 2) => C::HALT,
+            (_, _) => C::empty(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Register {
@@ -41,6 +86,7 @@ pub enum Register {
 bitflags! {
     #[derive(Default)]
     pub struct Controls: u32 {
+        /// Has no effect, see `crate::Controls::CLOCK`
         const CLOCK = 0b0000_0000_0000_0000_0000_0001;
         const RESET = 0b0000_0000_0000_0000_0000_0010;
         const RESET_RAM = 0b0000_0000_0000_0000_0000_0100;
@@ -66,6 +112,16 @@ bitflags! {
     }
 }
 
+impl Controls {
+    /// The outputs that drive the data bus, of which only one should be set at a time
+    pub const BUS_OUTPUTS: Self = Self::COUNTER_OUT
+        .union(Self::A_OUT)
+        .union(Self::B_OUT)
+        .union(Self::INSTRUCTION_OUT)
+        .union(Self::RAM_OUT)
+        .union(Self::ADDER_OUT);
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct Flags:u32 {
@@ -74,7 +130,10 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+serde_bits!(Controls);
+serde_bits!(Flags);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PuttPc {
     pub regs: [u8; 6],
     pub memory: [u8; 16],
@@ -82,6 +141,24 @@ pub struct PuttPc {
     pub flags_in: Flags,
     pub flags: Flags,
     pub micro: usize,
+    /// Whether to fault on bus contention, see `Machine::set_check_bus`
+    #[serde(default)]
+    pub check_bus: bool,
+    /// Saved in snapshots as text, unless it's the built-in microcode
+    #[serde(
+        default = "builtin_microcode",
+        serialize_with = "snapshot::custom_microcode::serialize",
+        deserialize_with = "deserialize_microcode"
+    )]
+    pub microcode: Arc<Microcode>,
+}
+
+fn builtin_microcode() -> Arc<Microcode> {
+    Arc::new(Microcode::builtin(Version::V1))
+}
+
+fn deserialize_microcode<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<Microcode>, D::Error> {
+    snapshot::custom_microcode::deserialize(Version::V1, d)
 }
 
 impl PuttPc {
@@ -94,20 +171,30 @@ impl PuttPc {
             flags_in: F::ZERO,
             flags: F::empty(),
             micro: 0,
+            check_bus: false,
+            microcode: builtin_microcode(),
         }
     }
 
-    #[must_use]
-    pub fn with_input(input: &[<Self as Machine>::Input]) -> Self {
+    /// Create a machine with the input provided
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in memory.
+    pub fn with_input(input: &[<Self as Machine>::Input]) -> Result<Self, MachineError> {
         let mut p = Self::new();
-        p.set_input(input);
-        p
+        p.set_input(input)?;
+        Ok(p)
     }
 
-    fn data_bus(&self) -> u8 {
+    fn data_bus(&self) -> Result<u8, MachineError> {
         let mut data = 0;
         if self.controls.contains(C::COUNTER_OUT) {
-            data |= self.regs[R::Counter as usize];
+            let counter = self.regs[R::Counter as usize];
+            if usize::from(counter) >= self.memory.len() {
+                return Err(MachineError::CounterOverflow { counter });
+            }
+            data |= counter;
         }
         if self.controls.contains(C::A_OUT) {
             data |= self.regs[R::A as usize];
@@ -119,66 +206,102 @@ impl PuttPc {
             data |= self.regs[R::Instruction as usize] & 0xF;
         }
         if self.controls.contains(C::RAM_OUT) {
-            data |= self.memory[self.regs[R::RamAddress as usize] as usize];
+            let address = self.regs[R::RamAddress as usize];
+            data |= self
+                .memory
+                .get(usize::from(address))
+                .ok_or(MachineError::AddressOutOfRange { address })?;
         }
         if self.controls.contains(C::ADDER_OUT) {
-            let (adder_sum, _) = if self.controls.contains(C::SUBTRACT) {
-                self.regs[R::A as usize].overflowing_sub(self.regs[R::B as usize])
-            } else {
-                self.regs[R::A as usize].overflowing_add(self.regs[R::B as usize])
-            };
+            let (adder_sum, _) = adder(
+                self.regs[R::A as usize],
+                self.regs[R::B as usize],
+                self.controls.contains(C::SUBTRACT),
+            );
 
             data |= adder_sum;
         };
 
-        data
+        Ok(data)
     }
 
-    fn flags_in_bus(&self) -> Flags {
-        let (adder_sum, adder_overflow) = if self.controls.contains(C::SUBTRACT) {
-            self.regs[R::A as usize].overflowing_sub(self.regs[R::B as usize])
-        } else {
-            self.regs[R::A as usize].overflowing_add(self.regs[R::B as usize])
-        };
-
-        let mut flags_in = F::empty();
-        if adder_sum == 0 {
-            flags_in |= F::ZERO;
+    /// The data bus, checking that a write to memory this step has a valid address, and that
+    /// only one output drives the bus if `check_bus` is set
+    fn checked_data_bus(&self) -> Result<u8, MachineError> {
+        if self.check_bus {
+            if let Some(sources) = microcode::bus_contention(Version::V1, self.controls.bits()) {
+                return Err(MachineError::BusContention {
+                    version: Version::V1,
+                    opcode: self.regs[R::Instruction as usize] >> 4,
+                    micro: self.micro,
+                    sources,
+                });
+            }
         }
-        if adder_overflow {
-            flags_in |= F::CARRY;
+
+        let data = self.data_bus()?;
+        if self.controls.contains(C::RAM_IN) {
+            let address = if self.controls.contains(C::RAM_ADDR_IN) {
+                data
+            } else {
+                self.regs[R::RamAddress as usize]
+            };
+            if usize::from(address) >= self.memory.len() {
+                return Err(MachineError::AddressOutOfRange { address });
+            }
         }
+        Ok(data)
+    }
 
+    fn flags_in_bus(&self) -> Flags {
+        let (_, flags_in) = adder(
+            self.regs[R::A as usize],
+            self.regs[R::B as usize],
+            self.controls.contains(C::SUBTRACT),
+        );
         flags_in
     }
 
-    #[allow(clippy::match_same_arms)]
-    fn controls_bus(&self) -> Controls {
-        let instr = I::try_from(self.regs[R::Instruction as usize] >> 4)
-            .expect("a u8 right shifted 4 is a valid instruction");
-        match (instr, self.micro) {
-            (_, 0) => C::COUNTER_OUT | C::RAM_ADDR_IN,
-            (_, 1) => C::COUNTER_INCREMENT | C::RAM_OUT | C::INSTRUCTION_IN,
-            (I::Ldav, 2) => C::INSTRUCTION_OUT | C::A_IN,
-            (I::Ldam, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
-            (I::Ldam, 3) => C::RAM_OUT | C::A_IN,
-            (I::Sta, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
-            (I::Sta, 3) => C::A_OUT | C::RAM_IN,
-            (I::Txb, 2) => C::A_OUT | C::B_IN,
-            (I::Add, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN,
-            (I::Add, 3) => C::RAM_OUT | C::B_IN,
-            (I::Add, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN,
-            (I::Sub, 2) => C::INSTRUCTION_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
-            (I::Sub, 3) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
-            (I::Sub, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::SUBTRACT,
-            (I::Jmp, 2) => C::INSTRUCTION_OUT | C::JUMP,
-            (I::Jz, 2) => C::INSTRUCTION_OUT | C::JUMP_IF_ZERO,
-            (I::Jc, 2) => C::INSTRUCTION_OUT | C::JUMP_IF_CARRY,
-            (I::Out, 2) => C::A_OUT | C::OUTPUT_IN,
-            (I::Hlt, 2) => C::HALT,
-            (_, _) => C::empty(),
+    /// Act on `RESET`, which clears the registers and flags, and `RESET_RAM`, which clears memory
+    fn apply_resets(&mut self) {
+        if self.controls.contains(C::RESET) {
+            self.regs = [0; 6];
+            self.flags = F::empty();
+            self.flags_in = self.flags_in_bus();
         }
+        if self.controls.contains(C::RESET_RAM) {
+            self.memory = [0; 16];
+        }
+    }
+
+    fn controls_bus(&self) -> Result<Controls, MachineError> {
+        let opcode = self.regs[R::Instruction as usize];
+        let instr = I::try_from(opcode >> 4).map_err(|_| MachineError::InvalidOpcode {
+            address: self.regs[R::RamAddress as usize],
+            opcode,
+        })?;
+        let controls = self.microcode.controls(instr as u8, self.micro);
+        Ok(Controls::from_bits_truncate(controls))
+    }
+}
+
+/// The output of the adder and the flags it produces
+fn adder(a: u8, b: u8, subtract: bool) -> (u8, Flags) {
+    let (sum, overflow) = if subtract {
+        a.overflowing_sub(b)
+    } else {
+        a.overflowing_add(b)
+    };
+
+    let mut flags = F::empty();
+    if sum == 0 {
+        flags |= F::ZERO;
+    }
+    if overflow {
+        flags |= F::CARRY;
     }
+
+    (sum, flags)
 }
 
 impl Default for PuttPc {
@@ -190,21 +313,112 @@ impl Default for PuttPc {
 impl Machine for PuttPc {
     type Input = u8;
     type Output = u8;
+    type Latches = (Controls, Flags, Flags, usize);
 
-    fn set_input(&mut self, input: &[Self::Input]) {
+    fn set_input(&mut self, input: &[Self::Input]) -> Result<(), MachineError> {
         let len = input.len();
-        assert!(len <= 16);
-        let memory = &mut self.memory[..len];
+        let max = self.memory.len();
+        let memory = self
+            .memory
+            .get_mut(..len)
+            .ok_or(MachineError::ProgramTooLarge { len, max })?;
         memory.copy_from_slice(input);
+        Ok(())
     }
 
     fn is_halted(&self) -> bool {
         self.controls.contains(C::HALT)
     }
 
-    fn step(&mut self) -> Option<Self::Output> {
+    fn micro(&self) -> usize {
+        self.micro
+    }
+
+    fn version(&self) -> Version {
+        Version::V1
+    }
+
+    fn regs(&self) -> &[u8] {
+        &self.regs
+    }
+
+    fn regs_mut(&mut self) -> &mut [u8] {
+        &mut self.regs
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn controls(&self) -> u32 {
+        self.controls.bits()
+    }
+
+    fn flags(&self) -> u32 {
+        self.flags.bits()
+    }
+
+    fn bus(&self) -> Option<u8> {
+        self.checked_data_bus().ok()
+    }
+
+    fn latches(&self) -> Self::Latches {
+        (self.controls, self.flags_in, self.flags, self.micro)
+    }
+
+    fn set_latches(&mut self, latches: Self::Latches) {
+        (self.controls, self.flags_in, self.flags, self.micro) = latches;
+    }
+
+    fn microcode(&self) -> &Microcode {
+        &self.microcode
+    }
+
+    fn reset(&mut self, kind: Reset) {
+        self.controls = match kind {
+            Reset::Soft => C::RESET,
+            Reset::PowerOn => C::RESET | C::RESET_RAM,
+        };
+        self.apply_resets();
+        self.micro = 0;
+        self.controls = self
+            .controls_bus()
+            .expect("opcode 0 is always a valid instruction");
+    }
+
+    fn set_check_bus(&mut self, check: bool) {
+        self.check_bus = check;
+    }
+
+    fn set_microcode(&mut self, microcode: Microcode) -> Result<(), MachineError> {
+        if microcode.version() != self.version() {
+            return Err(MachineError::WrongMicrocode {
+                machine: self.version(),
+                microcode: microcode.version(),
+            });
+        }
+        self.microcode = Arc::new(microcode);
+        if !self.is_halted() {
+            if let Ok(controls) = self.controls_bus() {
+                self.controls = controls;
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<Self::Output>, MachineError> {
         let mut out = None;
-        let data = self.data_bus();
+        let data = match self.checked_data_bus() {
+            Ok(data) => data,
+            Err(e) => {
+                self.controls.insert(C::HALT);
+                return Err(e);
+            }
+        };
 
         if self.controls.contains(C::RAM_ADDR_IN) {
             self.regs[R::RamAddress as usize] = data;
@@ -226,7 +440,8 @@ impl Machine for PuttPc {
             self.memory[self.regs[R::RamAddress as usize] as usize] = data;
         }
 
-        // Set the flags register and then calculate the NEXT step's flags_in
+        // Set the flags register and then calculate the NEXT step's flags_in, so FLAGS_IN
+        // latches the adder as it was at the start of the step. v3 calculates it first instead
         if self.controls.contains(C::FLAGS_IN) {
             self.flags = self.flags_in;
         }
@@ -239,31 +454,41 @@ impl Machine for PuttPc {
             self.regs[R::Counter as usize] = data & 0xF;
         }
         if self.controls.contains(C::COUNTER_INCREMENT) {
-            self.regs[R::Counter as usize] += 1;
+            self.regs[R::Counter as usize] = self.regs[R::Counter as usize].wrapping_add(1);
         }
 
-        self.controls = self.controls_bus();
+        // Resets go last, as they clear whatever the rest of the step latched
+        self.apply_resets();
 
+        // Advance past the microstep that just ran, then look up the controls for the next one
         self.micro += 1;
-        if self.micro > 4 {
+        if self.controls.contains(C::RESET) || self.micro >= MICRO_STEPS {
             self.micro = 0;
         }
 
-        out
+        self.controls = match self.controls_bus() {
+            Ok(controls) => controls,
+            Err(e) => {
+                self.controls = C::HALT;
+                return Err(e);
+            }
+        };
+
+        Ok(out)
     }
 
-    fn run(&mut self) -> Vec<Self::Output> {
+    fn run(&mut self) -> Result<Vec<Self::Output>, MachineError> {
         let mut output = Vec::new();
         while !self.is_halted() {
-            let out = self.step();
+            let out = self.step()?;
             output.extend(out);
         }
-        output
+        Ok(output)
     }
 }
 
 impl IntoIterator for PuttPc {
-    type Item = u8;
+    type Item = Result<u8, MachineError>;
     type IntoIter = IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -302,16 +527,152 @@ impl fmt::Display for PuttPc {
 pub struct IntoIter(PuttPc);
 
 impl Iterator for IntoIter {
-    type Item = u8;
+    type Item = Result<u8, MachineError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             if self.0.is_halted() {
                 break None;
             }
-            if let Some(out) = self.0.step() {
-                break Some(out);
+            match self.0.step() {
+                Ok(Some(out)) => break Some(Ok(out)),
+                Ok(None) => {}
+                Err(e) => break Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Runs whole instructions at once, as the built-in microcode would, instead of microsteps
+///
+/// This is much faster than stepping a `PuttPc`, and its registers, memory and flags match one
+/// at every instruction boundary. Custom microcode and `check_bus` have no equivalent here.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Interpreter {
+    pub regs: [u8; 6],
+    pub memory: [u8; 16],
+    pub flags: Flags,
+    pub halted: bool,
+}
+
+impl Interpreter {
+    #[must_use]
+    pub fn new() -> Self {
+        Interpreter {
+            regs: [0; 6],
+            memory: [0; 16],
+            flags: F::empty(),
+            halted: false,
+        }
+    }
+
+    /// Create an interpreter with the input provided
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in memory.
+    pub fn with_input(input: &[u8]) -> Result<Self, MachineError> {
+        let mut p = Self::new();
+        let len = input.len();
+        let max = p.memory.len();
+        let memory = p
+            .memory
+            .get_mut(..len)
+            .ok_or(MachineError::ProgramTooLarge { len, max })?;
+        memory.copy_from_slice(input);
+        Ok(p)
+    }
+
+    /// Run one instruction, returning its output
+    ///
+    /// Does nothing once halted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and halts, if the instruction faults.
+    pub fn step(&mut self) -> Result<Option<u8>, MachineError> {
+        if self.halted {
+            return Ok(None);
+        }
+        let out = self.execute();
+        if out.is_err() {
+            self.halted = true;
+        }
+        out
+    }
+
+    /// Run until halted, returning all output
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an instruction faults.
+    pub fn run(&mut self) -> Result<Vec<u8>, MachineError> {
+        let mut output = Vec::new();
+        while !self.halted {
+            output.extend(self.step()?);
+        }
+        Ok(output)
+    }
+
+    fn execute(&mut self) -> Result<Option<u8>, MachineError> {
+        let counter = self.regs[R::Counter as usize];
+        let opcode = *self
+            .memory
+            .get(usize::from(counter))
+            .ok_or(MachineError::CounterOverflow { counter })?;
+        self.regs[R::RamAddress as usize] = counter;
+        self.regs[R::Instruction as usize] = opcode;
+        self.regs[R::Counter as usize] = counter + 1;
+        let instr = I::try_from(opcode >> 4).map_err(|_| MachineError::InvalidOpcode {
+            address: counter,
+            opcode,
+        })?;
+
+        let operand = opcode & 0xF;
+        let a = self.regs[R::A as usize];
+        match instr {
+            I::Nop => {}
+            I::Ldav => self.regs[R::A as usize] = operand,
+            I::Ldam => {
+                self.regs[R::RamAddress as usize] = operand;
+                self.regs[R::A as usize] = self.memory[usize::from(operand)];
             }
+            I::Sta => {
+                self.regs[R::RamAddress as usize] = operand;
+                self.memory[usize::from(operand)] = a;
+            }
+            I::Txb => self.regs[R::B as usize] = a,
+            I::Add | I::Sub => {
+                let b = self.memory[usize::from(operand)];
+                self.regs[R::RamAddress as usize] = operand;
+                self.regs[R::B as usize] = b;
+                let (sum, flags) = adder(a, b, instr == I::Sub);
+                self.regs[R::A as usize] = sum;
+                self.flags = flags;
+            }
+            I::Jmp => self.regs[R::Counter as usize] = operand,
+            I::Jz => {
+                if self.flags.contains(F::ZERO) {
+                    self.regs[R::Counter as usize] = operand;
+                }
+            }
+            I::Jc => {
+                if self.flags.contains(F::CARRY) {
+                    self.regs[R::Counter as usize] = operand;
+                }
+            }
+            I::Out => {
+                self.regs[R::Output as usize] = a;
+                return Ok(Some(a));
+            }
+            I::Hlt => self.halted = true,
         }
+        Ok(None)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
     }
 }
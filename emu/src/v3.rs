@@ -1,13 +1,22 @@
-use crate::Machine;
+use crate::{
+    isa::{self, Operand},
+    microcode::{self, Microcode},
+    snapshot::{self, serde_bits},
+    Machine, MachineError, Reset, Version,
+};
 use bitflags::bitflags;
 use derive_try_from_primitive::TryFromPrimitive;
-use std::{convert::TryFrom, fmt};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{convert::TryFrom, fmt, sync::Arc};
 
 use Controls as C;
 use Flags as F;
 use Instruction as I;
 use Register as R;
 
+/// The number of microsteps in the microcode of each instruction
+pub const MICRO_STEPS: usize = 16;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Instruction {
@@ -49,6 +58,149 @@ pub enum Instruction {
     Hlt = 0xFF,
 }
 
+impl Instruction {
+    /// The mnemonic and operands of the instruction, as written in `ruledef.S`
+    #[must_use]
+    pub fn syntax(self) -> (&'static str, &'static [Operand]) {
+        isa::syntax(Version::V3, self as u8).expect("every instruction has a rule")
+    }
+
+    /// The built-in microcode: the controls for each microstep of the instruction
+    #[allow(clippy::match_same_arms)]
+    #[must_use]
+    pub fn controls(self, micro: usize) -> Controls {
+        match (self, micro) {
+            (_, 0) => C::COUNTER_OUT | C::RAM_ADDR_IN,
+            (_, 1) => C::COUNTER_INCREMENT | C::RAM_OUT | C::INSTRUCTION_IN,
+            (I::Nop, 2) => C::RESET_MICRO,
+            (I::MovAB, 2) => C::B_OUT | C::A_IN | C::RESET_MICRO,
+            (I::MovAV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovAV, 3) => C::RAM_OUT | C::A_IN | C::RESET_MICRO,
+            (I::MovAM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovAM, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::MovAM, 4) => C::RAM_OUT | C::A_IN | C::RESET_MICRO,
+            (I::MovBA, 2) => C::A_OUT | C::B_IN | C::RESET_MICRO,
+            (I::MovBV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovBV, 3) => C::RAM_OUT | C::B_IN | C::RESET_MICRO,
+            (I::MovBM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovBM, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::MovBM, 4) => C::RAM_OUT | C::B_IN | C::RESET_MICRO,
+            (I::MovMA, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMA, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::MovMA, 4) => C::A_OUT | C::RAM_IN | C::RESET_MICRO,
+            (I::MovMB, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMB, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::MovMB, 4) => C::B_OUT | C::RAM_IN | C::RESET_MICRO,
+            // B holds the destination address while the value is fetched into A
+            (I::MovMV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMV, 3) => C::RAM_OUT | C::B_IN,
+            (I::MovMV, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMV, 5) => C::RAM_OUT | C::A_IN,
+            (I::MovMV, 6) => C::B_OUT | C::RAM_ADDR_IN,
+            (I::MovMV, 7) => C::A_OUT | C::RAM_IN | C::RESET_MICRO,
+            // B holds the destination address while the source is read into A
+            (I::MovMM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMM, 3) => C::RAM_OUT | C::B_IN,
+            (I::MovMM, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::MovMM, 5) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::MovMM, 6) => C::RAM_OUT | C::A_IN,
+            (I::MovMM, 7) => C::B_OUT | C::RAM_ADDR_IN,
+            (I::MovMM, 8) => C::A_OUT | C::RAM_IN | C::RESET_MICRO,
+            (I::AddAB, 2) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddAV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddAV, 3) => C::RAM_OUT | C::B_IN,
+            (I::AddAV, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddAM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddAM, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddAM, 4) => C::RAM_OUT | C::B_IN,
+            (I::AddAM, 5) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddVB, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddVB, 3) => C::RAM_OUT | C::A_IN,
+            (I::AddVB, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddVV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddVV, 3) => C::RAM_OUT | C::A_IN,
+            (I::AddVV, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddVV, 5) => C::RAM_OUT | C::B_IN,
+            (I::AddVV, 6) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddVM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddVM, 3) => C::RAM_OUT | C::A_IN,
+            (I::AddVM, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddVM, 5) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddVM, 6) => C::RAM_OUT | C::B_IN,
+            (I::AddVM, 7) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddMB, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddMB, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddMB, 4) => C::RAM_OUT | C::A_IN,
+            (I::AddMB, 5) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddMV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddMV, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddMV, 4) => C::RAM_OUT | C::A_IN,
+            (I::AddMV, 5) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddMV, 6) => C::RAM_OUT | C::B_IN,
+            (I::AddMV, 7) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::AddMM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddMM, 3) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddMM, 4) => C::RAM_OUT | C::A_IN,
+            (I::AddMM, 5) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::AddMM, 6) => C::RAM_OUT | C::RAM_ADDR_IN,
+            (I::AddMM, 7) => C::RAM_OUT | C::B_IN,
+            (I::AddMM, 8) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO,
+            (I::SubAB, 2) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubAV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubAV, 3) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubAV, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubAM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubAM, 3) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubAM, 4) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubAM, 5) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubVB, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubVB, 3) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubVB, 4) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubVV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubVV, 3) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubVV, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubVV, 5) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubVV, 6) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubVM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubVM, 3) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubVM, 4) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubVM, 5) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubVM, 6) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubVM, 7) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubMB, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubMB, 3) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubMB, 4) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubMB, 5) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubMV, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubMV, 3) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubMV, 4) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubMV, 5) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubMV, 6) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubMV, 7) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::SubMM, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubMM, 3) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubMM, 4) => C::RAM_OUT | C::A_IN | C::SUBTRACT,
+            (I::SubMM, 5) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT | C::SUBTRACT,
+            (I::SubMM, 6) => C::RAM_OUT | C::RAM_ADDR_IN | C::SUBTRACT,
+            (I::SubMM, 7) => C::RAM_OUT | C::B_IN | C::SUBTRACT,
+            (I::SubMM, 8) => C::ADDER_OUT | C::A_IN | C::FLAGS_IN | C::RESET_MICRO | C::SUBTRACT,
+            (I::Jmp, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::Jmp, 3) => C::RAM_OUT | C::JUMP | C::RESET_MICRO,
+            (I::Jz, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::Jz, 3) => C::RAM_OUT | C::JUMP_IF_ZERO | C::RESET_MICRO,
+            (I::Jnz, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::Jnz, 3) => C::RAM_OUT | C::JUMP_IF_NOT_ZERO | C::RESET_MICRO,
+            (I::Jc, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::Jc, 3) => C::RAM_OUT | C::JUMP_IF_CARRY | C::RESET_MICRO,
+            (I::Jnc, 2) => C::COUNTER_OUT | C::RAM_ADDR_IN | C::COUNTER_INCREMENT,
+            (I::Jnc, 3) => C::RAM_OUT | C::JUMP_IF_NOT_CARRY | C::RESET_MICRO,
+            (I::Out, 2) => C::A_OUT | C::OUTPUT_IN | C::RESET_MICRO,
+            (I::Hlt, 2) => C::HALT | C::RESET_MICRO,
+            (_, _) => C::empty(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Register {
@@ -63,32 +215,45 @@ pub enum Register {
 bitflags! {
     #[derive(Default)]
     pub struct Controls: u32 {
-        const CLOCK = 0b0000_0000_0000_0000_0000_0001;
-        const RESET = 0b0000_0000_0000_0000_0000_0010;
-        const RESET_RAM = 0b0000_0000_0000_0000_0000_0100;
-        const HALT = 0b0000_0000_0000_0000_0000_1000;
-        const SUBTRACT = 0b0000_0000_0000_0000_0001_0000;
-        const ADDER_OUT = 0b0000_0000_0000_0000_0010_0000;
-        const RAM_ADDR_IN = 0b0000_0000_0000_0000_0100_0000;
-        const OUTPUT_IN = 0b0000_0000_0000_0000_1000_0000;
-        const A_IN = 0b0000_0000_0000_0001_0000_0000;
-        const A_OUT = 0b0000_0000_0000_0010_0000_0000;
-        const B_IN = 0b0000_0000_0000_0100_0000_0000;
-        const B_OUT = 0b0000_0000_0000_1000_0000_0000;
-        const INSTRUCTION_IN = 0b0000_0000_0001_0000_0000_0000;
-        const INSTRUCTION_OUT = 0b0000_0000_0010_0000_0000_0000;
-        const RAM_IN = 0b0000_0000_0100_0000_0000_0000;
-        const RAM_OUT = 0b0000_0000_1000_0000_0000_0000;
-        const COUNTER_OUT = 0b0000_0001_0000_0000_0000_0000;
-        const COUNTER_INCREMENT = 0b0000_0010_0000_0000_0000_0000;
-        const JUMP = 0b0000_0100_0000_0000_0000_0000;
-        const JUMP_IF_ZERO = 0b0000_1000_0000_0000_0000_0000;
-        const JUMP_IF_CARRY = 0b0001_0000_0000_0000_0000_0000;
-        const FLAGS_IN = 0b0010_0000_0000_0000_0000_0000;
-        const RESET_MICRO = 0b0100_0000_0000_0000_0000_0000;
+        /// Has no effect, see `crate::Controls::CLOCK`
+        const CLOCK = 0b0000_0000_0000_0000_0000_0000_0001;
+        const RESET = 0b0000_0000_0000_0000_0000_0000_0010;
+        const RESET_RAM = 0b0000_0000_0000_0000_0000_0000_0100;
+        const HALT = 0b0000_0000_0000_0000_0000_0000_1000;
+        const SUBTRACT = 0b0000_0000_0000_0000_0000_0001_0000;
+        const ADDER_OUT = 0b0000_0000_0000_0000_0000_0010_0000;
+        const RAM_ADDR_IN = 0b0000_0000_0000_0000_0000_0100_0000;
+        const OUTPUT_IN = 0b0000_0000_0000_0000_0000_1000_0000;
+        const A_IN = 0b0000_0000_0000_0000_0001_0000_0000;
+        const A_OUT = 0b0000_0000_0000_0000_0010_0000_0000;
+        const B_IN = 0b0000_0000_0000_0000_0100_0000_0000;
+        const B_OUT = 0b0000_0000_0000_0000_1000_0000_0000;
+        const INSTRUCTION_IN = 0b0000_0000_0000_0001_0000_0000_0000;
+        const INSTRUCTION_OUT = 0b0000_0000_0000_0010_0000_0000_0000;
+        const RAM_IN = 0b0000_0000_0000_0100_0000_0000_0000;
+        const RAM_OUT = 0b0000_0000_0000_1000_0000_0000_0000;
+        const COUNTER_OUT = 0b0000_0000_0001_0000_0000_0000_0000;
+        const COUNTER_INCREMENT = 0b0000_0000_0010_0000_0000_0000_0000;
+        const JUMP = 0b0000_0000_0100_0000_0000_0000_0000;
+        const JUMP_IF_ZERO = 0b0000_0000_1000_0000_0000_0000_0000;
+        const JUMP_IF_CARRY = 0b0000_0001_0000_0000_0000_0000_0000;
+        const FLAGS_IN = 0b0000_0010_0000_0000_0000_0000_0000;
+        const RESET_MICRO = 0b0000_0100_0000_0000_0000_0000_0000;
+        const JUMP_IF_NOT_ZERO = 0b0000_1000_0000_0000_0000_0000_0000;
+        const JUMP_IF_NOT_CARRY = 0b0001_0000_0000_0000_0000_0000_0000;
     }
 }
 
+impl Controls {
+    /// The outputs that drive the data bus, of which only one should be set at a time
+    pub const BUS_OUTPUTS: Self = Self::COUNTER_OUT
+        .union(Self::A_OUT)
+        .union(Self::B_OUT)
+        .union(Self::INSTRUCTION_OUT)
+        .union(Self::RAM_OUT)
+        .union(Self::ADDER_OUT);
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct Flags:u32 {
@@ -97,14 +262,36 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+serde_bits!(Controls);
+serde_bits!(Flags);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PuttPc {
     pub regs: [u8; 6],
+    #[serde(with = "crate::snapshot::array")]
     pub memory: [u8; 256],
     pub controls: Controls,
     pub flags_in: Flags,
     pub flags: Flags,
     pub micro: usize,
+    /// Whether to fault on bus contention, see `Machine::set_check_bus`
+    #[serde(default)]
+    pub check_bus: bool,
+    /// Saved in snapshots as text, unless it's the built-in microcode
+    #[serde(
+        default = "builtin_microcode",
+        serialize_with = "snapshot::custom_microcode::serialize",
+        deserialize_with = "deserialize_microcode"
+    )]
+    pub microcode: Arc<Microcode>,
+}
+
+fn builtin_microcode() -> Arc<Microcode> {
+    Arc::new(Microcode::builtin(Version::V3))
+}
+
+fn deserialize_microcode<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<Microcode>, D::Error> {
+    snapshot::custom_microcode::deserialize(Version::V3, d)
 }
 
 impl PuttPc {
@@ -117,17 +304,23 @@ impl PuttPc {
             flags_in: F::ZERO,
             flags: F::empty(),
             micro: 0,
+            check_bus: false,
+            microcode: builtin_microcode(),
         }
     }
 
-    #[must_use]
-    pub fn with_input(input: &[<Self as Machine>::Input]) -> Self {
+    /// Create a machine with the input provided
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in memory.
+    pub fn with_input(input: &[<Self as Machine>::Input]) -> Result<Self, MachineError> {
         let mut p = Self::new();
-        p.set_input(input);
-        p
+        p.set_input(input)?;
+        Ok(p)
     }
 
-    fn data_bus(&self) -> u8 {
+    fn data_bus(&self) -> Result<u8, MachineError> {
         let mut data = 0;
         if self.controls.contains(C::COUNTER_OUT) {
             data |= self.regs[R::Counter as usize];
@@ -139,86 +332,105 @@ impl PuttPc {
             data |= self.regs[R::B as usize];
         }
         if self.controls.contains(C::INSTRUCTION_OUT) {
-            data |= self.regs[R::Instruction as usize] & 0xF;
+            data |= self.regs[R::Instruction as usize];
         }
         if self.controls.contains(C::RAM_OUT) {
-            data |= self.memory[self.regs[R::RamAddress as usize] as usize];
+            let address = self.regs[R::RamAddress as usize];
+            data |= self
+                .memory
+                .get(usize::from(address))
+                .ok_or(MachineError::AddressOutOfRange { address })?;
         }
         if self.controls.contains(C::ADDER_OUT) {
-            let (adder_sum, _) = if self.controls.contains(C::SUBTRACT) {
-                self.regs[R::A as usize].overflowing_sub(self.regs[R::B as usize])
-            } else {
-                self.regs[R::A as usize].overflowing_add(self.regs[R::B as usize])
-            };
+            let (adder_sum, _) = adder(
+                self.regs[R::A as usize],
+                self.regs[R::B as usize],
+                self.controls.contains(C::SUBTRACT),
+            );
 
             data |= adder_sum;
         };
 
-        data
+        Ok(data)
     }
 
-    fn flags_in_bus(&self) -> Flags {
-        let (adder_sum, adder_overflow) = if self.controls.contains(C::SUBTRACT) {
-            self.regs[R::A as usize].overflowing_sub(self.regs[R::B as usize])
-        } else {
-            self.regs[R::A as usize].overflowing_add(self.regs[R::B as usize])
-        };
-
-        let mut flags_in = F::empty();
-        if adder_sum == 0 {
-            flags_in |= F::ZERO;
+    /// The data bus, checking that a write to memory this step has a valid address, and that
+    /// only one output drives the bus if `check_bus` is set
+    fn checked_data_bus(&self) -> Result<u8, MachineError> {
+        if self.check_bus {
+            if let Some(sources) = microcode::bus_contention(Version::V3, self.controls.bits()) {
+                return Err(MachineError::BusContention {
+                    version: Version::V3,
+                    opcode: self.regs[R::Instruction as usize],
+                    micro: self.micro,
+                    sources,
+                });
+            }
         }
-        if adder_overflow {
-            flags_in |= F::CARRY;
+
+        let data = self.data_bus()?;
+        if self.controls.contains(C::RAM_IN) {
+            let address = if self.controls.contains(C::RAM_ADDR_IN) {
+                data
+            } else {
+                self.regs[R::RamAddress as usize]
+            };
+            if usize::from(address) >= self.memory.len() {
+                return Err(MachineError::AddressOutOfRange { address });
+            }
         }
+        Ok(data)
+    }
 
+    fn flags_in_bus(&self) -> Flags {
+        let (_, flags_in) = adder(
+            self.regs[R::A as usize],
+            self.regs[R::B as usize],
+            self.controls.contains(C::SUBTRACT),
+        );
         flags_in
     }
 
-    fn controls_bus(&self) -> Controls {
-        let instr = I::try_from(self.regs[R::Instruction as usize]).unwrap();
-        match (instr, self.micro) {
-            (_, 0) => C::COUNTER_OUT | C::RAM_ADDR_IN,
-            (_, 1) => C::COUNTER_INCREMENT | C::RAM_OUT | C::INSTRUCTION_IN,
-            (I::Nop, 2) => C::RESET_MICRO,
-            (I::MovAB, _) => todo!(),
-            (I::MovAV, _) => todo!(),
-            (I::MovAM, _) => todo!(),
-            (I::MovBA, _) => todo!(),
-            (I::MovBV, _) => todo!(),
-            (I::MovBM, _) => todo!(),
-            (I::MovMA, _) => todo!(),
-            (I::MovMB, _) => todo!(),
-            (I::MovMV, _) => todo!(),
-            (I::MovMM, _) => todo!(),
-            (I::AddAB, _) => todo!(),
-            (I::AddAV, _) => todo!(),
-            (I::AddAM, _) => todo!(),
-            (I::AddVB, _) => todo!(),
-            (I::AddVV, _) => todo!(),
-            (I::AddVM, _) => todo!(),
-            (I::AddMB, _) => todo!(),
-            (I::AddMV, _) => todo!(),
-            (I::AddMM, _) => todo!(),
-            (I::SubAB, _) => todo!(),
-            (I::SubAV, _) => todo!(),
-            (I::SubAM, _) => todo!(),
-            (I::SubVB, _) => todo!(),
-            (I::SubVV, _) => todo!(),
-            (I::SubVM, _) => todo!(),
-            (I::SubMB, _) => todo!(),
-            (I::SubMV, _) => todo!(),
-            (I::SubMM, _) => todo!(),
-            (I::Jmp, _) => todo!(),
-            (I::Jz, _) => todo!(),
-            (I::Jnz, _) => todo!(),
-            (I::Jc, _) => todo!(),
-            (I::Jnc, _) => todo!(),
-            (I::Out, 2) => C::A_OUT | C::OUTPUT_IN | C::RESET_MICRO,
-            (I::Hlt, 2) => C::HALT | C::RESET_MICRO,
-            (_, _) => C::empty(),
+    /// Act on `RESET`, which clears the registers and flags, and `RESET_RAM`, which clears memory
+    fn apply_resets(&mut self) {
+        if self.controls.contains(C::RESET) {
+            self.regs = [0; 6];
+            self.flags = F::empty();
+            self.flags_in = self.flags_in_bus();
+        }
+        if self.controls.contains(C::RESET_RAM) {
+            self.memory = [0; 256];
         }
     }
+
+    fn controls_bus(&self) -> Result<Controls, MachineError> {
+        let opcode = self.regs[R::Instruction as usize];
+        let instr = I::try_from(opcode).map_err(|opcode| MachineError::InvalidOpcode {
+            address: self.regs[R::RamAddress as usize],
+            opcode,
+        })?;
+        let controls = self.microcode.controls(instr as u8, self.micro);
+        Ok(Controls::from_bits_truncate(controls))
+    }
+}
+
+/// The output of the adder and the flags it produces
+fn adder(a: u8, b: u8, subtract: bool) -> (u8, Flags) {
+    let (sum, overflow) = if subtract {
+        a.overflowing_sub(b)
+    } else {
+        a.overflowing_add(b)
+    };
+
+    let mut flags = F::empty();
+    if sum == 0 {
+        flags |= F::ZERO;
+    }
+    if overflow {
+        flags |= F::CARRY;
+    }
+
+    (sum, flags)
 }
 
 impl Default for PuttPc {
@@ -230,21 +442,120 @@ impl Default for PuttPc {
 impl Machine for PuttPc {
     type Input = u8;
     type Output = u8;
+    type Latches = (Controls, Flags, Flags, usize);
 
     fn is_halted(&self) -> bool {
         self.controls.contains(C::HALT)
     }
 
-    fn set_input(&mut self, input: &[Self::Input]) {
+    fn micro(&self) -> usize {
+        self.micro
+    }
+
+    fn version(&self) -> Version {
+        Version::V3
+    }
+
+    fn regs(&self) -> &[u8] {
+        &self.regs
+    }
+
+    fn regs_mut(&mut self) -> &mut [u8] {
+        &mut self.regs
+    }
+
+    fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn controls(&self) -> u32 {
+        self.controls.bits()
+    }
+
+    fn flags(&self) -> u32 {
+        self.flags.bits()
+    }
+
+    fn bus(&self) -> Option<u8> {
+        self.checked_data_bus().ok()
+    }
+
+    fn latches(&self) -> Self::Latches {
+        (self.controls, self.flags_in, self.flags, self.micro)
+    }
+
+    fn set_latches(&mut self, latches: Self::Latches) {
+        (self.controls, self.flags_in, self.flags, self.micro) = latches;
+    }
+
+    fn microcode(&self) -> &Microcode {
+        &self.microcode
+    }
+
+    fn reset(&mut self, kind: Reset) {
+        self.controls = match kind {
+            Reset::Soft => C::RESET,
+            Reset::PowerOn => C::RESET | C::RESET_RAM,
+        };
+        self.apply_resets();
+        self.micro = 0;
+        self.controls = self
+            .controls_bus()
+            .expect("opcode 0 is always a valid instruction");
+    }
+
+    fn set_check_bus(&mut self, check: bool) {
+        self.check_bus = check;
+    }
+
+    fn set_microcode(&mut self, microcode: Microcode) -> Result<(), MachineError> {
+        if microcode.version() != self.version() {
+            return Err(MachineError::WrongMicrocode {
+                machine: self.version(),
+                microcode: microcode.version(),
+            });
+        }
+        self.microcode = Arc::new(microcode);
+        if !self.is_halted() {
+            if let Ok(controls) = self.controls_bus() {
+                self.controls = controls;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_input(&mut self, input: &[Self::Input]) -> Result<(), MachineError> {
         let len = input.len();
-        assert!(len <= 16);
-        let memory = &mut self.memory[..len];
+        let max = self.memory.len();
+        let memory = self
+            .memory
+            .get_mut(..len)
+            .ok_or(MachineError::ProgramTooLarge { len, max })?;
         memory.copy_from_slice(input);
+        Ok(())
     }
 
-    fn step(&mut self) -> Option<Self::Output> {
+    fn step(&mut self) -> Result<Option<Self::Output>, MachineError> {
         let mut out = None;
-        let data = self.data_bus();
+        let data = match self.checked_data_bus() {
+            Ok(data) => data,
+            Err(e) => {
+                self.controls.insert(C::HALT);
+                return Err(e);
+            }
+        };
+
+        // Unlike v1 and v2, which latch the flags_in calculated at the end of the step before,
+        // this calculates flags_in from this step's A, B and SUBTRACT. v3's microcode only
+        // asserts SUBTRACT in the same step as FLAGS_IN, so the flags match the adder's output
+        self.flags_in = self.flags_in_bus();
+        if self.controls.contains(C::FLAGS_IN) {
+            self.flags = self.flags_in;
+        }
 
         if self.controls.contains(C::RAM_ADDR_IN) {
             self.regs[R::RamAddress as usize] = data;
@@ -266,44 +577,50 @@ impl Machine for PuttPc {
             self.memory[self.regs[R::RamAddress as usize] as usize] = data;
         }
 
-        // Set the flags register and then calculate the NEXT step's flags_in
-        if self.controls.contains(C::FLAGS_IN) {
-            self.flags = self.flags_in;
-        }
-        self.flags_in = self.flags_in_bus();
-
         if self.controls.contains(C::JUMP)
             || (self.controls.contains(C::JUMP_IF_ZERO) && self.flags.contains(F::ZERO))
+            || (self.controls.contains(C::JUMP_IF_NOT_ZERO) && !self.flags.contains(F::ZERO))
             || (self.controls.contains(C::JUMP_IF_CARRY) && self.flags.contains(F::CARRY))
+            || (self.controls.contains(C::JUMP_IF_NOT_CARRY) && !self.flags.contains(F::CARRY))
         {
-            self.regs[R::Counter as usize] = data & 0xF;
+            self.regs[R::Counter as usize] = data;
         }
         if self.controls.contains(C::COUNTER_INCREMENT) {
-            self.regs[R::Counter as usize] += 1;
+            self.regs[R::Counter as usize] = self.regs[R::Counter as usize].wrapping_add(1);
         }
 
-        self.controls = self.controls_bus();
+        // Resets go last, as they clear whatever the rest of the step latched
+        self.apply_resets();
 
+        // Advance past the microstep that just ran, then look up the controls for the next one
         self.micro += 1;
-        if self.controls.contains(C::RESET_MICRO) || self.micro > 4 {
+        if self.controls.intersects(C::RESET_MICRO | C::RESET) || self.micro >= MICRO_STEPS {
             self.micro = 0;
         }
 
-        out
+        self.controls = match self.controls_bus() {
+            Ok(controls) => controls,
+            Err(e) => {
+                self.controls = C::HALT;
+                return Err(e);
+            }
+        };
+
+        Ok(out)
     }
 
-    fn run(&mut self) -> Vec<Self::Output> {
+    fn run(&mut self) -> Result<Vec<Self::Output>, MachineError> {
         let mut output = Vec::new();
         while !self.is_halted() {
-            let out = self.step();
+            let out = self.step()?;
             output.extend(out);
         }
-        output
+        Ok(output)
     }
 }
 
 impl IntoIterator for PuttPc {
-    type Item = u8;
+    type Item = Result<u8, MachineError>;
     type IntoIter = IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -322,7 +639,7 @@ impl fmt::Display for PuttPc {
 
         writeln!(f, "Memory")?;
         for (i, v) in self.memory.iter().enumerate() {
-            writeln!(f, "  {i:<2} {v:>3} ({v:08b})")?;
+            writeln!(f, "  {i:<3} {v:>3} ({v:08b})")?;
         }
 
         writeln!(f, "Controls")?;
@@ -341,16 +658,205 @@ impl fmt::Display for PuttPc {
 pub struct IntoIter(PuttPc);
 
 impl Iterator for IntoIter {
-    type Item = u8;
+    type Item = Result<u8, MachineError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             if self.0.is_halted() {
                 break None;
             }
-            if let Some(out) = self.0.step() {
-                break Some(out);
+            match self.0.step() {
+                Ok(Some(out)) => break Some(Ok(out)),
+                Ok(None) => {}
+                Err(e) => break Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Runs whole instructions at once, as the built-in microcode would, instead of microsteps
+///
+/// This is much faster than stepping a `PuttPc`, and its registers, memory and flags match one
+/// at every instruction boundary. Custom microcode and `check_bus` have no equivalent here.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Interpreter {
+    pub regs: [u8; 6],
+    pub memory: [u8; 256],
+    pub flags: Flags,
+    pub halted: bool,
+}
+
+impl Interpreter {
+    #[must_use]
+    pub fn new() -> Self {
+        Interpreter {
+            regs: [0; 6],
+            memory: [0; 256],
+            flags: F::empty(),
+            halted: false,
+        }
+    }
+
+    /// Create an interpreter with the input provided
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input doesn't fit in memory.
+    pub fn with_input(input: &[u8]) -> Result<Self, MachineError> {
+        let mut p = Self::new();
+        let len = input.len();
+        let max = p.memory.len();
+        let memory = p
+            .memory
+            .get_mut(..len)
+            .ok_or(MachineError::ProgramTooLarge { len, max })?;
+        memory.copy_from_slice(input);
+        Ok(p)
+    }
+
+    /// Run one instruction, returning its output
+    ///
+    /// Does nothing once halted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and halts, if the instruction faults.
+    pub fn step(&mut self) -> Result<Option<u8>, MachineError> {
+        if self.halted {
+            return Ok(None);
+        }
+        let out = self.execute();
+        if out.is_err() {
+            self.halted = true;
+        }
+        out
+    }
+
+    /// Run until halted, returning all output
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an instruction faults.
+    pub fn run(&mut self) -> Result<Vec<u8>, MachineError> {
+        let mut output = Vec::new();
+        while !self.halted {
+            output.extend(self.step()?);
+        }
+        Ok(output)
+    }
+
+    fn execute(&mut self) -> Result<Option<u8>, MachineError> {
+        let counter = self.regs[R::Counter as usize];
+        let opcode = self.fetch();
+        self.regs[R::Instruction as usize] = opcode;
+        let instr = I::try_from(opcode).map_err(|opcode| MachineError::InvalidOpcode {
+            address: counter,
+            opcode,
+        })?;
+
+        match instr {
+            I::Nop => {}
+            I::MovAB => self.regs[R::A as usize] = self.regs[R::B as usize],
+            I::MovAV => self.regs[R::A as usize] = self.fetch(),
+            I::MovAM => self.regs[R::A as usize] = self.fetch_indirect(),
+            I::MovBA => self.regs[R::B as usize] = self.regs[R::A as usize],
+            I::MovBV => self.regs[R::B as usize] = self.fetch(),
+            I::MovBM => self.regs[R::B as usize] = self.fetch_indirect(),
+            I::MovMA | I::MovMB => {
+                let address = self.fetch();
+                self.regs[R::RamAddress as usize] = address;
+                self.memory[usize::from(address)] = if instr == I::MovMA {
+                    self.regs[R::A as usize]
+                } else {
+                    self.regs[R::B as usize]
+                };
+            }
+            // B holds the destination address while the source is read into A
+            I::MovMV | I::MovMM => {
+                let address = self.fetch();
+                self.regs[R::B as usize] = address;
+                self.regs[R::A as usize] = if instr == I::MovMV {
+                    self.fetch()
+                } else {
+                    self.fetch_indirect()
+                };
+                self.regs[R::RamAddress as usize] = address;
+                self.memory[usize::from(address)] = self.regs[R::A as usize];
+            }
+            I::AddAB | I::SubAB => {}
+            I::AddAV | I::SubAV => self.regs[R::B as usize] = self.fetch(),
+            I::AddAM | I::SubAM => self.regs[R::B as usize] = self.fetch_indirect(),
+            I::AddVB | I::SubVB => self.regs[R::A as usize] = self.fetch(),
+            I::AddVV | I::SubVV => {
+                self.regs[R::A as usize] = self.fetch();
+                self.regs[R::B as usize] = self.fetch();
+            }
+            I::AddVM | I::SubVM => {
+                self.regs[R::A as usize] = self.fetch();
+                self.regs[R::B as usize] = self.fetch_indirect();
+            }
+            I::AddMB | I::SubMB => self.regs[R::A as usize] = self.fetch_indirect(),
+            I::AddMV | I::SubMV => {
+                self.regs[R::A as usize] = self.fetch_indirect();
+                self.regs[R::B as usize] = self.fetch();
             }
+            I::AddMM | I::SubMM => {
+                self.regs[R::A as usize] = self.fetch_indirect();
+                self.regs[R::B as usize] = self.fetch_indirect();
+            }
+            I::Jmp | I::Jz | I::Jnz | I::Jc | I::Jnc => {
+                let address = self.fetch();
+                let jump = match instr {
+                    I::Jz => self.flags.contains(F::ZERO),
+                    I::Jnz => !self.flags.contains(F::ZERO),
+                    I::Jc => self.flags.contains(F::CARRY),
+                    I::Jnc => !self.flags.contains(F::CARRY),
+                    _ => true,
+                };
+                if jump {
+                    self.regs[R::Counter as usize] = address;
+                }
+            }
+            I::Out => {
+                let a = self.regs[R::A as usize];
+                self.regs[R::Output as usize] = a;
+                return Ok(Some(a));
+            }
+            I::Hlt => self.halted = true,
+        }
+
+        // Additions are 0x1_ and subtractions 0x2_, which end by storing the adder in A
+        if let 0x1 | 0x2 = opcode >> 4 {
+            let (sum, flags) = adder(
+                self.regs[R::A as usize],
+                self.regs[R::B as usize],
+                opcode >> 4 == 0x2,
+            );
+            self.regs[R::A as usize] = sum;
+            self.flags = flags;
         }
+        Ok(None)
+    }
+
+    /// Read the byte at the counter and move past it, as `COUNTER_OUT | RAM_ADDR_IN |
+    /// COUNTER_INCREMENT` then `RAM_OUT` do
+    fn fetch(&mut self) -> u8 {
+        let counter = self.regs[R::Counter as usize];
+        self.regs[R::RamAddress as usize] = counter;
+        self.regs[R::Counter as usize] = counter.wrapping_add(1);
+        self.memory[usize::from(counter)]
+    }
+
+    /// Read the byte at the address at the counter, and move past the address
+    fn fetch_indirect(&mut self) -> u8 {
+        let address = self.fetch();
+        self.regs[R::RamAddress as usize] = address;
+        self.memory[usize::from(address)]
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,188 @@
+//! Value Change Dump traces of a `Machine`, for viewing in a waveform viewer like GTKWave
+
+use crate::{flag_names, microcode, register_name, Machine};
+use std::io::{self, Write};
+
+/// A signal in the dump
+struct Signal {
+    id: String,
+    width: usize,
+    /// The value last written, so only changes are dumped
+    last: Option<Option<u64>>,
+}
+
+/// A tracer that writes one timestep of a VCD file per step of a machine
+///
+/// Each timestep holds the controls and bus of the step about to run, and the registers, flags
+/// and micro counter going into it.
+pub struct Vcd<W: Write> {
+    w: W,
+    time: u64,
+    controls: Vec<(Signal, u32)>,
+    bus: Signal,
+    registers: Vec<Signal>,
+    flags: Vec<(Signal, u32)>,
+    micro: Signal,
+}
+
+impl<W: Write> Vcd<W> {
+    /// Start a trace of `machine`, writing the header
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing fails.
+    pub fn new(mut w: W, machine: &impl Machine) -> io::Result<Self> {
+        let mut ids = (0..).map(id);
+        let mut signal = |width| Signal {
+            id: ids.next().expect("ids are endless"),
+            width,
+            last: None,
+        };
+
+        let controls: Vec<_> = microcode::control_names(machine.version())
+            .into_iter()
+            .map(|(name, bit)| (name, signal(1), bit))
+            .collect();
+        let bus = signal(8);
+        let registers: Vec<_> = (0..machine.regs().len())
+            .map(|r| (register_name(r), signal(8)))
+            .collect();
+        let flags: Vec<_> = flag_names()
+            .into_iter()
+            .map(|(name, bit)| (name, signal(1), bit))
+            .collect();
+        // `micro` can hold `steps` itself, after the last step and before it wraps
+        let steps = machine.microcode().steps();
+        let micro = signal(steps.next_power_of_two().trailing_zeros() as usize + 1);
+
+        writeln!(w, "$version {} $end", env!("CARGO_PKG_DESCRIPTION"))?;
+        writeln!(w, "$timescale 1 s $end")?;
+        writeln!(w, "$scope module puttpc_{:?} $end", machine.version())?;
+        writeln!(w, "$scope module controls $end")?;
+        for (name, s, _) in &controls {
+            writeln!(w, "$var wire 1 {} {} $end", s.id, name)?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$scope module registers $end")?;
+        for (name, s) in &registers {
+            writeln!(w, "$var reg 8 {} {} $end", s.id, name)?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$scope module flags $end")?;
+        for (name, s, _) in &flags {
+            writeln!(w, "$var reg 1 {} {} $end", s.id, name)?;
+        }
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$var wire 8 {} bus $end", bus.id)?;
+        writeln!(w, "$var reg {} {} micro $end", micro.width, micro.id)?;
+        writeln!(w, "$upscope $end")?;
+        writeln!(w, "$enddefinitions $end")?;
+
+        Ok(Self {
+            w,
+            time: 0,
+            controls: controls.into_iter().map(|(_, s, bit)| (s, bit)).collect(),
+            bus,
+            registers: registers.into_iter().map(|(_, s)| s).collect(),
+            flags: flags.into_iter().map(|(_, s, bit)| (s, bit)).collect(),
+            micro,
+        })
+    }
+
+    /// Write the machine's state as the next timestep, which should be done before each step
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing fails.
+    pub fn record(&mut self, machine: &impl Machine) -> io::Result<()> {
+        writeln!(self.w, "#{}", self.time)?;
+        self.time += 1;
+
+        let controls = machine.controls();
+        for (s, bit) in &mut self.controls {
+            dump(&mut self.w, s, Some(u64::from(controls & *bit != 0)))?;
+        }
+        dump(&mut self.w, &mut self.bus, machine.bus().map(u64::from))?;
+        for (s, value) in self.registers.iter_mut().zip(machine.regs()) {
+            dump(&mut self.w, s, Some(u64::from(*value)))?;
+        }
+        let flags = machine.flags();
+        for (s, bit) in &mut self.flags {
+            dump(&mut self.w, s, Some(u64::from(flags & *bit != 0)))?;
+        }
+        dump(&mut self.w, &mut self.micro, Some(machine.micro() as u64))
+    }
+
+    /// Finish the trace, returning the writer
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing fails.
+    pub fn finish(mut self) -> io::Result<W> {
+        writeln!(self.w, "#{}", self.time)?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Write a signal's value if it changed, with `None` as unknown
+fn dump(w: &mut impl Write, s: &mut Signal, value: Option<u64>) -> io::Result<()> {
+    if s.last == Some(value) {
+        return Ok(());
+    }
+    s.last = Some(value);
+    match (s.width, value) {
+        (1, Some(v)) => writeln!(w, "{}{}", v, s.id),
+        (1, None) => writeln!(w, "x{}", s.id),
+        (_, Some(v)) => writeln!(w, "b{:b} {}", v, s.id),
+        (_, None) => writeln!(w, "bx {}", s.id),
+    }
+}
+
+/// The identifier of the `n`th signal, from the printable characters `!` to `~`
+fn id(mut n: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push(char::from(b'!' + (n % 94) as u8));
+        n /= 94;
+        if n == 0 {
+            break id;
+        }
+        n -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v3::PuttPc;
+
+    #[test]
+    fn header_and_changes() {
+        // mov %a 5; out; hlt
+        let mut machine = PuttPc::with_input(&[0x02, 0x05, 0xe0, 0xff]).unwrap();
+        let mut vcd = Vcd::new(Vec::new(), &machine).unwrap();
+        for _ in 0..3 {
+            vcd.record(&machine).unwrap();
+            machine.step().unwrap();
+        }
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+        // v3 has 16 steps, and `micro` can reach 16 after the last
+        assert!(text.contains("$var reg 5 C micro $end\n"));
+        assert!(text.contains("$var reg 8 ; Counter $end\n"));
+
+        let (_, changes) = text.split_once("$enddefinitions $end\n").unwrap();
+        let (initial, changes) = changes.split_once("#1\n").unwrap();
+        // Every signal starts out dumped, like the counter driving the bus for the fetch
+        assert!(initial.starts_with("#0\n"));
+        assert!(initial.contains("\n11\n"));
+        assert!(initial.ends_with("b0 C\n"));
+        // Then only what changed: the fetch loads the instruction from memory
+        assert_eq!(
+            changes,
+            "0'\n1-\n10\n01\n12\nb10 :\nb1 C\n\
+             #2\n1'\n0-\n00\n11\nb1 :\nb1 ;\nb10 @\nb10 C\n\
+             #3\n"
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Placing ROMs into a Minecraft world
+//!
+//! A `Layout` says which block holds each bit, and is usually read from a JSON file such as
+//!
+//! ```json
+//! { "origin": [100, 64, 200], "address_step": [0, 0, 2], "bit_step": [2, 0, 0] }
+//! ```
+
+use crate::{
+    nbt::Tag,
+    region::{self, Region},
+    Rom,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error,
+    fmt::{self, Write as _},
+    io::Write as _,
+    str::FromStr,
+};
+
+/// A block and its properties, written as in commands, like `repeater[facing=north]`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block {
+    /// The namespaced id of the block, like `minecraft:repeater`
+    pub name: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Block {
+    /// Whether `other` is this block, with at least this block's properties
+    #[must_use]
+    pub fn matches(&self, other: &Block) -> bool {
+        self.name == other.name
+            && self
+                .properties
+                .iter()
+                .all(|(k, v)| other.properties.get(k) == Some(v))
+    }
+}
+
+impl FromStr for Block {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, properties) = match s.split_once('[') {
+            Some((name, rest)) => {
+                let properties = rest
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("missing `]` in block `{}`", s))?;
+                let properties = properties
+                    .split(',')
+                    .filter(|p| !p.trim().is_empty())
+                    .map(|p| match p.split_once('=') {
+                        Some((k, v)) => Ok((k.trim().to_string(), v.trim().to_string())),
+                        None => Err(format!("invalid property `{}` in block `{}`", p, s)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                (name, properties)
+            }
+            None => (s, BTreeMap::new()),
+        };
+        let name = name.trim();
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "[]=,".contains(c)) {
+            return Err(format!("invalid block `{}`", s));
+        }
+        let name = if name.contains(':') {
+            name.to_string()
+        } else {
+            format!("minecraft:{}", name)
+        };
+        Ok(Self { name, properties })
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.properties.is_empty() {
+            let properties: Vec<_> = self
+                .properties
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            write!(f, "[{}]", properties.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Region(region::Error),
+    InvalidBlock(String),
+    /// A bit's block is neither `Layout::on` nor `Layout::off`
+    UnexpectedBlock {
+        pos: [i32; 3],
+        block: Block,
+    },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Region(_) => write!(f, "could not read the world"),
+            Self::InvalidBlock(e) => write!(f, "{}", e),
+            Self::UnexpectedBlock {
+                pos: [x, y, z],
+                block,
+            } => write!(f, "expected a bit at {} {} {}, found {}", x, y, z, block),
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Region(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where each bit of a ROM is in the world, and which blocks represent a bit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// The block holding bit 0 of address 0
+    pub origin: [i32; 3],
+    /// The offset from a bit of one address to the same bit of the next
+    pub address_step: [i32; 3],
+    /// The offset from one bit of an address to the next, least significant first
+    pub bit_step: [i32; 3],
+    /// The block for a set bit
+    #[serde(default = "default_on")]
+    pub on: String,
+    /// The block for a clear bit
+    #[serde(default = "default_off")]
+    pub off: String,
+    /// Whether `origin` is relative to where a function is run, rather than absolute
+    #[serde(default)]
+    pub relative: bool,
+    /// The data version written to structure files, which should match the game
+    #[serde(default = "default_data_version")]
+    pub data_version: i32,
+}
+
+fn default_on() -> String {
+    "minecraft:redstone_block".to_string()
+}
+
+fn default_off() -> String {
+    "minecraft:air".to_string()
+}
+
+fn default_data_version() -> i32 {
+    // 1.20.1
+    3465
+}
+
+impl Layout {
+    /// The position of a bit of an address
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    #[must_use]
+    pub fn position(&self, address: usize, bit: usize) -> [i32; 3] {
+        let (address, bit) = (address as i32, bit as i32);
+        let mut pos = self.origin;
+        for (i, p) in pos.iter_mut().enumerate() {
+            *p += address * self.address_step[i] + bit * self.bit_step[i];
+        }
+        pos
+    }
+
+    /// Every bit of a ROM, as its position and whether it's set
+    fn blocks<'a>(&'a self, rom: &'a Rom) -> impl Iterator<Item = ([i32; 3], bool)> + 'a {
+        rom.words.iter().flat_map(move |word| {
+            word.bits
+                .iter()
+                .enumerate()
+                .map(move |(bit, set)| (self.position(word.address, bit), *set))
+        })
+    }
+
+    /// A datapack function of `setblock` commands that writes a ROM into the world
+    #[must_use]
+    pub fn mcfunction(&self, rom: &Rom) -> String {
+        let prefix = if self.relative { "~" } else { "" };
+        let mut function = String::new();
+        for ([x, y, z], set) in self.blocks(rom) {
+            let block = if set { &self.on } else { &self.off };
+            writeln!(
+                function,
+                "setblock {p}{} {p}{} {p}{} {}",
+                x,
+                y,
+                z,
+                block,
+                p = prefix
+            )
+            .expect("writing to a String can't fail");
+        }
+        function
+    }
+
+    /// A gzipped structure file holding a ROM, to be loaded with a structure block
+    ///
+    /// The structure starts at the lowest corner of the ROM, so `origin` is only used to place
+    /// the bits relative to each other.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `on` or `off` isn't a valid block.
+    pub fn structure(&self, rom: &Rom) -> Result<Vec<u8>, String> {
+        let structure = self.structure_tag(rom)?;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        structure
+            .write_root("", &mut gz)
+            .and_then(|()| gz.flush())
+            .expect("writing to a Vec can't fail");
+        Ok(gz.finish().expect("writing to a Vec can't fail"))
+    }
+
+    /// The root tag of `structure`
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    fn structure_tag(&self, rom: &Rom) -> Result<Tag, String> {
+        let blocks: Vec<_> = self.blocks(rom).collect();
+        let mut min = [i32::MAX; 3];
+        let mut max = [i32::MIN; 3];
+        for (pos, _) in &blocks {
+            for i in 0..3 {
+                min[i] = min[i].min(pos[i]);
+                max[i] = max[i].max(pos[i]);
+            }
+        }
+
+        let mut palette = BTreeMap::new();
+        let blocks = blocks
+            .into_iter()
+            .map(|(pos, set)| {
+                let block = if set { &self.on } else { &self.off };
+                let len = palette.len();
+                let state = *palette.entry(block.clone()).or_insert(len as i32);
+                Tag::Compound(vec![
+                    (
+                        "pos".to_string(),
+                        Tag::List((0..3).map(|i| Tag::Int(pos[i] - min[i])).collect()),
+                    ),
+                    ("state".to_string(), Tag::Int(state)),
+                ])
+            })
+            .collect();
+        let mut palette: Vec<_> = palette.into_iter().collect();
+        palette.sort_by_key(|(_, state)| *state);
+
+        let size = if min[0] > max[0] {
+            vec![Tag::Int(0); 3]
+        } else {
+            (0..3).map(|i| Tag::Int(max[i] - min[i] + 1)).collect()
+        };
+        Ok(Tag::Compound(vec![
+            ("DataVersion".to_string(), Tag::Int(self.data_version)),
+            ("size".to_string(), Tag::List(size)),
+            (
+                "palette".to_string(),
+                Tag::List(
+                    palette
+                        .into_iter()
+                        .map(|(block, _)| palette_entry(&block))
+                        .collect::<Result<_, _>>()?,
+                ),
+            ),
+            ("blocks".to_string(), Tag::List(blocks)),
+            ("entities".to_string(), Tag::List(Vec::new())),
+        ]))
+    }
+
+    /// Read `size` bytes of memory back out of the world
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a bit can't be read from the region, or its block is neither `on`
+    /// nor `off`.
+    pub fn read_memory(&self, region: &mut Region, size: usize) -> Result<Vec<u8>, ReadError> {
+        let on: Block = self.on.parse().map_err(ReadError::InvalidBlock)?;
+        let off: Block = self.off.parse().map_err(ReadError::InvalidBlock)?;
+
+        (0..size)
+            .map(|address| {
+                (0..8).try_fold(0, |byte, bit| {
+                    let pos = self.position(address, bit);
+                    let block = region.block(pos).map_err(ReadError::Region)?;
+                    if on.matches(&block) {
+                        Ok(byte | 1 << bit)
+                    } else if off.matches(&block) {
+                        Ok(byte)
+                    } else {
+                        Err(ReadError::UnexpectedBlock { pos, block })
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// A block as an entry in a structure's palette
+fn palette_entry(block: &str) -> Result<Tag, String> {
+    let block: Block = block.parse()?;
+    let mut entry = vec![("Name".to_string(), Tag::String(block.name))];
+    if !block.properties.is_empty() {
+        let properties = block
+            .properties
+            .into_iter()
+            .map(|(k, v)| (k, Tag::String(v)))
+            .collect();
+        entry.push(("Properties".to_string(), Tag::Compound(properties)));
+    }
+    Ok(Tag::Compound(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Version;
+
+    fn layout(on: &str, off: &str) -> Layout {
+        Layout {
+            origin: [0, 0, 0],
+            address_step: [0, 0, 1],
+            bit_step: [1, 0, 0],
+            on: on.to_string(),
+            off: off.to_string(),
+            relative: false,
+            data_version: default_data_version(),
+        }
+    }
+
+    fn palette(structure: &Tag) -> &[Tag] {
+        match structure {
+            Tag::Compound(tags) => match tags.iter().find(|(name, _)| name == "palette") {
+                Some((_, Tag::List(palette))) => palette,
+                _ => panic!("structure has no palette"),
+            },
+            _ => panic!("structure isn't a compound"),
+        }
+    }
+
+    fn string(s: &str) -> Tag {
+        Tag::String(s.to_string())
+    }
+
+    #[test]
+    fn palette_has_names_and_properties() {
+        let rom = Rom::program(Version::V2, &[0x0f]).unwrap();
+        let structure = layout("repeater[facing=north, delay=2]", "air")
+            .structure_tag(&rom)
+            .unwrap();
+        assert_eq!(
+            palette(&structure),
+            [
+                Tag::Compound(vec![
+                    ("Name".to_string(), string("minecraft:repeater")),
+                    (
+                        "Properties".to_string(),
+                        Tag::Compound(vec![
+                            ("delay".to_string(), string("2")),
+                            ("facing".to_string(), string("north")),
+                        ]),
+                    ),
+                ]),
+                Tag::Compound(vec![("Name".to_string(), string("minecraft:air"))]),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_blocks_are_errors() {
+        let rom = Rom::program(Version::V2, &[0x0f]).unwrap();
+        for block in [
+            "",
+            "repeater[facing=north",
+            "repeater[facing]",
+            "[facing=north]",
+            "redstone block",
+        ] {
+            assert!(
+                layout(block, "air").structure(&rom).is_err(),
+                "`{}` was accepted",
+                block
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Runs random programs on both the microcoded machines and the instruction-level interpreters,
+//! checking they agree at every instruction boundary
+
+use puttpc_emu::{isa::Operand, v1, v2, v3, Machine, MachineError};
+use std::convert::TryFrom;
+
+/// The number of random programs to run on each version
+const PROGRAMS: u64 = 2000;
+
+/// The most instructions to run of each program, as many of them loop forever
+const MAX_INSTRUCTIONS: usize = 500;
+
+/// A xorshift generator, so a failing program can be reproduced from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[(self.next() % items.len() as u64) as usize]
+    }
+}
+
+/// The state the two models must agree on
+trait Interpreter {
+    fn step(&mut self) -> Result<Option<u8>, MachineError>;
+    fn regs(&self) -> &[u8];
+    fn memory(&self) -> &[u8];
+    fn flags(&self) -> u32;
+    fn is_halted(&self) -> bool;
+}
+
+macro_rules! interpreter {
+    ($version:ident) => {
+        impl Interpreter for $version::Interpreter {
+            fn step(&mut self) -> Result<Option<u8>, MachineError> {
+                self.step()
+            }
+            fn regs(&self) -> &[u8] {
+                &self.regs
+            }
+            fn memory(&self) -> &[u8] {
+                &self.memory
+            }
+            fn flags(&self) -> u32 {
+                self.flags.bits()
+            }
+            fn is_halted(&self) -> bool {
+                self.halted
+            }
+        }
+    };
+}
+
+interpreter!(v1);
+interpreter!(v2);
+interpreter!(v3);
+
+/// Run a program on both models an instruction at a time, panicking when they disagree
+fn compare(seed: u64, mut machine: impl Machine<Output = u8>, mut interpreter: impl Interpreter) {
+    for instruction in 0..MAX_INSTRUCTIONS {
+        let mut output = None;
+        let expected = loop {
+            match machine.step() {
+                Ok(out) => {
+                    output = output.or(out);
+                    if machine.micro() == 0 || machine.is_halted() {
+                        break Ok(output);
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        let actual = interpreter.step();
+
+        let context = format!("seed {}, instruction {}", seed, instruction);
+        assert_eq!(actual, expected, "{}: output", context);
+        assert_eq!(interpreter.regs(), machine.regs(), "{}: registers", context);
+        assert_eq!(
+            interpreter.memory(),
+            machine.memory(),
+            "{}: memory",
+            context
+        );
+        assert_eq!(interpreter.flags(), machine.flags(), "{}: flags", context);
+        assert_eq!(
+            interpreter.is_halted(),
+            machine.is_halted(),
+            "{}: halted",
+            context
+        );
+        if machine.is_halted() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn v1() {
+    let opcodes: Vec<_> = (0..16)
+        .filter(|o| v1::Instruction::try_from(*o).is_ok())
+        .collect();
+    for seed in 0..PROGRAMS {
+        let mut rng = Rng::new(seed);
+        // Mostly valid instructions, with the odd invalid one
+        let program: Vec<_> = (0..16)
+            .map(|_| match rng.next() % 8 {
+                0 => rng.byte(),
+                _ => rng.pick(&opcodes) << 4 | rng.byte() & 0xF,
+            })
+            .collect();
+        compare(
+            seed,
+            v1::PuttPc::with_input(&program).unwrap(),
+            v1::Interpreter::with_input(&program).unwrap(),
+        );
+    }
+}
+
+#[test]
+fn v2() {
+    for seed in 0..PROGRAMS {
+        let mut rng = Rng::new(seed);
+        // Every byte is a valid instruction
+        let program: Vec<_> = (0..16).map(|_| rng.byte()).collect();
+        compare(
+            seed,
+            v2::PuttPc::with_input(&program).unwrap(),
+            v2::Interpreter::with_input(&program).unwrap(),
+        );
+    }
+}
+
+#[test]
+fn v3() {
+    let instructions: Vec<_> = (0..=u8::MAX)
+        .filter_map(|o| v3::Instruction::try_from(o).ok())
+        .collect();
+    for seed in 0..PROGRAMS {
+        let mut rng = Rng::new(seed);
+        // Instructions with their operands up front, half pointing back into the program, and
+        // random data after
+        let mut program = Vec::new();
+        while program.len() < 64 {
+            let instruction = rng.pick(&instructions);
+            program.push(instruction as u8);
+            for operand in instruction.syntax().1 {
+                if let Operand::Value | Operand::Address = operand {
+                    let byte = rng.byte();
+                    program.push(if rng.byte() < 128 { byte % 64 } else { byte });
+                }
+            }
+        }
+        program.resize(256, 0);
+        for byte in &mut program[64..] {
+            *byte = rng.byte();
+        }
+        compare(
+            seed,
+            v3::PuttPc::with_input(&program).unwrap(),
+            v3::Interpreter::with_input(&program).unwrap(),
+        );
+    }
+}
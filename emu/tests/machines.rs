@@ -0,0 +1,86 @@
+//! Checks of the microcoded machines that hold for every version
+
+use puttpc_emu::{v1, v2, v3, Machine, Microcode};
+
+/// Steps into the fetch of a `nop`, moves the counter to 255, and checks the increment wraps it
+fn counter_wraps(mut machine: impl Machine, counter: usize) {
+    machine.step().unwrap();
+    machine.regs_mut()[counter] = 255;
+    machine.step().unwrap();
+    assert_eq!(machine.regs()[counter], 0);
+}
+
+#[test]
+fn v1_counter_wraps() {
+    counter_wraps(
+        v1::PuttPc::with_input(&[0x00]).unwrap(),
+        v1::Register::Counter as usize,
+    );
+}
+
+#[test]
+fn v2_counter_wraps() {
+    counter_wraps(
+        v2::PuttPc::with_input(&[0x00]).unwrap(),
+        v2::Register::Counter as usize,
+    );
+}
+
+#[test]
+fn v3_counter_wraps() {
+    counter_wraps(
+        v3::PuttPc::with_input(&[0x00]).unwrap(),
+        v3::Register::Counter as usize,
+    );
+}
+
+/// Runs `nop` with microcode that asserts `controls` in its third step, after filling the
+/// registers besides the instruction, and memory, with ones
+fn run_reset<M: Machine>(mut machine: M, instruction: usize, controls: &str) -> M {
+    let microcode = format!("0x00 2: {}", controls);
+    let microcode = Microcode::parse(machine.version(), &microcode).unwrap();
+    machine.set_microcode(microcode).unwrap();
+    for (i, reg) in machine.regs_mut().iter_mut().enumerate() {
+        *reg = u8::from(i != instruction);
+    }
+    machine.memory_mut().fill(1);
+
+    machine.step().unwrap();
+    machine.step().unwrap();
+    assert_eq!(machine.micro(), 2);
+    machine.step().unwrap();
+    machine
+}
+
+fn reset_clears_registers(machine: impl Machine, instruction: usize) {
+    let machine = run_reset(machine, instruction, "RESET");
+    assert!(machine.regs().iter().all(|&r| r == 0));
+    assert_eq!(machine.micro(), 0);
+    assert!(machine.memory().iter().all(|&m| m == 1));
+}
+
+fn reset_ram_clears_memory(machine: impl Machine, instruction: usize) {
+    let machine = run_reset(machine, instruction, "RESET_RAM");
+    assert!(machine.memory().iter().all(|&m| m == 0));
+}
+
+#[test]
+fn v1_reset() {
+    let instruction = v1::Register::Instruction as usize;
+    reset_clears_registers(v1::PuttPc::new(), instruction);
+    reset_ram_clears_memory(v1::PuttPc::new(), instruction);
+}
+
+#[test]
+fn v2_reset() {
+    let instruction = v2::Register::Instruction as usize;
+    reset_clears_registers(v2::PuttPc::new(), instruction);
+    reset_ram_clears_memory(v2::PuttPc::new(), instruction);
+}
+
+#[test]
+fn v3_reset() {
+    let instruction = v3::Register::Instruction as usize;
+    reset_clears_registers(v3::PuttPc::new(), instruction);
+    reset_ram_clears_memory(v3::PuttPc::new(), instruction);
+}
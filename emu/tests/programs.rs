@@ -0,0 +1,39 @@
+//! Assembles every program in `asm/` with `; expect:` comments, checking that it outputs them
+
+use fs_err as fs;
+use puttpc_emu::{asm, golden};
+use std::path::Path;
+
+/// The most steps a program may run
+const MAX_STEPS: u64 = 100_000;
+
+#[test]
+fn programs() {
+    let mut paths = Vec::new();
+    asm::find_sources(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("../asm"),
+        &mut paths,
+    )
+    .unwrap();
+
+    let mut tested = 0;
+    let mut failures = Vec::new();
+    for path in paths {
+        let expected = match golden::expected_output(&fs::read_to_string(&path).unwrap()) {
+            Ok(Some(expected)) => expected,
+            Ok(None) => continue,
+            Err(e) => {
+                failures.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let version = asm::version_of(&path).expect("programs are in a directory per version");
+        if let Err(e) = golden::check(&path, version, &expected, MAX_STEPS, None, true) {
+            failures.push(format!("{}: {}", path.display(), e));
+        }
+        tested += 1;
+    }
+
+    assert!(tested > 0, "no programs have `; expect:` comments");
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}